@@ -0,0 +1,963 @@
+////////////////
+/// 
+/// apu.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/Audio.html - overview
+/// https://gbdev.io/pandocs/Audio_Registers.html - registers
+/// https://gbdev.io/pandocs/Audio_details.html - frame sequencer, DACs, mixing
+/// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware - trigger, sweep and length details
+/// 
+/// 0xFF10 - 0xFF14 : channel 1, pulse with frequency sweep   NR10 - NR14
+/// 0xFF16 - 0xFF19 : channel 2, pulse                         NR21 - NR24
+/// 0xFF1A - 0xFF1E : channel 3, wave                          NR30 - NR34
+/// 0xFF20 - 0xFF23 : channel 4, noise                         NR41 - NR44
+/// 0xFF24 : NR50 - master volume, bits 4-6 left, bits 0-2 right
+/// 0xFF25 : NR51 - panning, bits 4-7 channel 1-4 left, bits 0-3 right
+/// 0xFF26 : NR52 - bit 7 power, bits 0-3 channel 1-4 on (read only)
+/// 0xFF30 - 0xFF3F : wave RAM, 32 4 bit samples, high nibble first
+/// 
+/// Frame sequencer, clocked at 512 Hz by DIV bit 4 falling:
+///     Step   0   1   2   3   4   5   6   7
+///     Length on      on      on      on
+///     Sweep          on              on
+///     Volume                             on
+/// 
+/// Each channel puts out a 4 bit value which its DAC turns into -1.0 - 1.0, a channel
+/// with its DAC off is silent. The DAC outputs are panned, summed and scaled by the master
+/// volume, averaged down to the host sample rate and run through a high-pass filter like
+/// the capacitors on the real output.
+/// 
+/// The mix and each channel's DAC output can also be streamed to WAV files while running.
+/// 
+/// For debugging, channels can be muted or soloed, which only changes what goes into the
+/// mix. Whenever any channel is soloed, only the soloed channels are heard. The last
+/// SCOPE_LENGTH host samples of each channel's DAC output are kept for oscilloscope views,
+/// muted or not.
+/// 
+use std::io;
+use crate::wav::Recorder;
+
+/// Machine cycles per second
+pub const CYCLE_RATE: u32 = 1_048_576;
+
+/// Channels, 0 - 3 in the API for channels 1 - 4
+pub const CHANNELS: usize = 4;
+/// Host samples of each channel kept for oscilloscopes
+pub const SCOPE_LENGTH: usize = 2048;
+
+const DUTY: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Read back masks, unused and write-only bits read as 1. Indexed from 0xFF10
+const READ_MASK: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+];
+
+/// Volume envelope of the pulse and noise channels, NRx2
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self { initial: 0, increase: false, period: 0, volume: 0, timer: 0 }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.initial = data >> 4;
+        self.increase = data & 0x08 != 0;
+        self.period = data & 0x07;
+    }
+
+    fn read(&self) -> u8 {
+        (self.initial << 4) | if self.increase {0x08} else {0x00} | self.period
+    }
+
+    /// The DAC is on as long as the envelope isn't set to 0 going down
+    fn dac_on(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn step(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Length counter, disables its channel when it runs out
+struct Length {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Self { max, counter: 0, enabled: false }
+    }
+
+    /// NRx1 holds max minus the length
+    fn load(&mut self, data: u8) {
+        self.counter = self.max - data as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns false once the channel has to be switched off
+    fn step(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        return true;
+    }
+}
+
+/// Frequency sweep of channel 1, NR10
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self { period: 0, negate: false, shift: 0, timer: 0, shadow: 0, enabled: false }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+    }
+
+    fn read(&self) -> u8 {
+        (self.period << 4) | if self.negate {0x08} else {0x00} | self.shift
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 counts as 8
+        self.timer = if self.period == 0 {8} else {self.period};
+    }
+
+    /// Next frequency, above 2047 switches the channel off
+    fn calculate(&self) -> u16 {
+        let delta: u16 = self.shadow >> self.shift;
+        if self.negate {self.shadow.wrapping_sub(delta)} else {self.shadow + delta}
+    }
+}
+
+struct Pulse {
+    on: bool,
+    duty: u8,
+    position: u8,
+    frequency: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn new(sweep: bool) -> Self {
+        Self {
+            on: false,
+            duty: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep {Some(Sweep::new())} else {None},
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.on = self.envelope.dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency: u16 = self.frequency;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // The overflow check runs straight away when there is a shift
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                self.on = false;
+            }
+        }
+    }
+
+    fn sweep_step(&mut self) {
+        let sweep: &mut Sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency: u16 = sweep.calculate();
+        if frequency > 2047 {
+            self.on = false;
+            return;
+        }
+        if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // Checked again with the new frequency, but not written back
+            if sweep.calculate() > 2047 {
+                self.on = false;
+            }
+        }
+    }
+
+    fn clock(&mut self, clocks: u32) {
+        let mut clocks: u32 = clocks;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 7;
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.on || DUTY[self.duty as usize] & (0x80 >> self.position) == 0 {
+            return 0;
+        }
+        return self.envelope.volume;
+    }
+}
+
+struct Wave {
+    on: bool,
+    dac: bool,
+    volume: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Self {
+            on: false,
+            dac: false,
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.on = self.dac;
+        self.length.trigger();
+        // The first sample played is the second one, sample 0 is only reached after wrapping
+        self.timer = self.period() + 6;
+        self.position = 0;
+    }
+
+    fn clock(&mut self, clocks: u32) {
+        let mut clocks: u32 = clocks;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 31;
+            let byte: u8 = self.ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 {byte >> 4} else {byte & 0x0F};
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.on {
+            return 0;
+        }
+        // NR32: 0 mute, 1 100%, 2 50%, 3 25%
+        match self.volume {
+            0 => 0,
+            volume => self.sample >> (volume - 1),
+        }
+    }
+}
+
+struct Noise {
+    on: bool,
+    shift: u8,
+    width7: bool,
+    divisor: u8,
+    timer: u32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            on: false,
+            shift: 0,
+            width7: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn write_nr43(&mut self, data: u8) {
+        self.shift = data >> 4;
+        self.width7 = data & 0x08 != 0;
+        self.divisor = data & 0x07;
+    }
+
+    fn read_nr43(&self) -> u8 {
+        (self.shift << 4) | if self.width7 {0x08} else {0x00} | self.divisor
+    }
+
+    fn period(&self) -> u32 {
+        let divisor: u32 = if self.divisor == 0 {8} else {self.divisor as u32 * 16};
+        divisor << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.on = self.envelope.dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn clock(&mut self, clocks: u32) {
+        let mut clocks: u32 = clocks;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            let bit: u16 = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width7 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+        self.timer -= clocks;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.on || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        return self.envelope.volume;
+    }
+}
+
+/*
+ * power: NR52 bit 7, while off every register but wave RAM reads 0 and ignores writes
+ * step: frame sequencer step that runs next
+ * nr50, nr51: master volume and panning
+ * sample_rate: host samples per second
+ * sample_clock: machine cycles into the current host sample, scaled by sample_rate
+ * sum, count: mixed output added up since the last host sample
+ * channel_sum: DAC output of each channel added up since the last host sample
+ * capacitor: state of the high-pass filter, left and right
+ * charge: fraction of the capacitor charge kept per host sample
+ * samples: interleaved stereo samples waiting for the frontend
+ * recorder: WAV files the output is streamed to, if recording
+ * muted, soloed: channels left out of the mix, and channels heard on their own
+ * scope: ring buffer of each channel's recent output, scope_pos is the oldest sample
+ */
+pub struct Apu {
+    power: bool,
+    step: u8,
+    ch1: Pulse,
+    ch2: Pulse,
+    ch3: Wave,
+    ch4: Noise,
+    nr50: u8,
+    nr51: u8,
+    sample_rate: u32,
+    sample_clock: u32,
+    sum: [f32; 2],
+    count: u32,
+    channel_sum: [f32; 4],
+    capacitor: [f32; 2],
+    charge: f32,
+    samples: Vec<f32>,
+    recorder: Option<Recorder>,
+    muted: [bool; CHANNELS],
+    soloed: [bool; CHANNELS],
+    scope: [[f32; SCOPE_LENGTH]; CHANNELS],
+    scope_pos: usize,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        // State left behind by the DMG boot ROM
+        let mut apu: Apu = Self {
+            power: true,
+            step: 0,
+            ch1: Pulse::new(true),
+            ch2: Pulse::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            nr50: 0x77,
+            nr51: 0xF3,
+            sample_rate,
+            sample_clock: 0,
+            sum: [0.0; 2],
+            count: 0,
+            channel_sum: [0.0; 4],
+            capacitor: [0.0; 2],
+            // 0.999958 per clock as on the DMG, raised to the clocks per host sample
+            charge: 0.999958f32.powf(CYCLE_RATE as f32 * 4.0 / sample_rate as f32),
+            samples: Vec::new(),
+            recorder: None,
+            muted: [false; CHANNELS],
+            soloed: [false; CHANNELS],
+            scope: [[0.0; SCOPE_LENGTH]; CHANNELS],
+            scope_pos: 0,
+        };
+        apu.ch1.duty = 2;
+        apu.ch1.envelope.write(0xF3);
+        apu.ch1.on = true;
+        return apu;
+    }
+
+    /// Advance by one machine cycle
+    pub fn tick(&mut self) {
+        if self.power {
+            self.ch1.clock(4);
+            self.ch2.clock(4);
+            self.ch3.clock(4);
+            self.ch4.clock(4);
+        }
+        self.mix();
+    }
+
+    /// DIV bit 4 fell, run the next frame sequencer step
+    pub fn frame_step(&mut self) {
+        if !self.power {
+            return;
+        }
+        if self.step & 1 == 0 {
+            if !self.ch1.length.step() {
+                self.ch1.on = false;
+            }
+            if !self.ch2.length.step() {
+                self.ch2.on = false;
+            }
+            if !self.ch3.length.step() {
+                self.ch3.on = false;
+            }
+            if !self.ch4.length.step() {
+                self.ch4.on = false;
+            }
+        }
+        if self.step == 2 || self.step == 6 {
+            self.ch1.sweep_step();
+        }
+        if self.step == 7 {
+            self.ch1.envelope.step();
+            self.ch2.envelope.step();
+            self.ch4.envelope.step();
+        }
+        self.step = (self.step + 1) & 7;
+    }
+
+    /// DAC output of each channel, -1.0 - 1.0, 0.0 with the DAC off
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |on: bool, value: u8| if on {value as f32 / 7.5 - 1.0} else {0.0};
+        [
+            dac(self.ch1.envelope.dac_on(), self.ch1.output()),
+            dac(self.ch2.envelope.dac_on(), self.ch2.output()),
+            dac(self.ch3.dac, self.ch3.output()),
+            dac(self.ch4.envelope.dac_on(), self.ch4.output()),
+        ]
+    }
+
+    /// Pan and sum the channels, and hand out a host sample whenever one is due
+    fn mix(&mut self) {
+        let outputs: [f32; 4] = self.dac_outputs();
+        let solo: bool = self.soloed.contains(&true);
+        let mut left: f32 = 0.0;
+        let mut right: f32 = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            self.channel_sum[i] += output;
+            if self.muted[i] || (solo && !self.soloed[i]) {
+                continue;
+            }
+            if self.nr51 & (0x10 << i) != 0 {
+                left += output;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                right += output;
+            }
+        }
+        // 4 channels, master volume 1 - 8
+        self.sum[0] += left / 4.0 * (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        self.sum[1] += right / 4.0 * ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+        self.count += 1;
+
+        self.sample_clock += self.sample_rate;
+        if self.sample_clock >= CYCLE_RATE {
+            self.sample_clock -= CYCLE_RATE;
+            let mut mix: [f32; 2] = [0.0; 2];
+            for side in 0..2 {
+                mix[side] = self.high_pass(side, self.sum[side] / self.count as f32);
+            }
+            let channels: [f32; 4] = self.channel_sum.map(|sum| sum / self.count as f32);
+            self.samples.extend_from_slice(&mix);
+            for (i, sample) in channels.iter().enumerate() {
+                self.scope[i][self.scope_pos] = *sample;
+            }
+            self.scope_pos = (self.scope_pos + 1) % SCOPE_LENGTH;
+            self.record(mix, channels);
+            self.sum = [0.0; 2];
+            self.channel_sum = [0.0; 4];
+            self.count = 0;
+        }
+    }
+
+    fn record(&mut self, mix: [f32; 2], channels: [f32; 4]) {
+        let recorder: &mut Recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        // A failed write ends the recording rather than the emulation
+        if let Err(err) = recorder.write(mix, channels) {
+            eprintln!("Failed to write audio recording, stopping it: {}", err);
+            self.recorder = None;
+        }
+    }
+
+    /// Stream the output to WAV files from now on, replacing any recording in progress
+    pub fn start_recording(&mut self, recorder: Recorder) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(recorder);
+        return Ok(());
+    }
+
+    /// Finish the WAV files being recorded to, if any
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        return match self.recorder.take() {
+            Some(mut recorder) => recorder.finish(),
+            None => Ok(()),
+        };
+    }
+
+    /// Channels past CHANNELS are ignored
+    pub fn set_muted(&mut self, channel: usize, muted: bool) {
+        if channel >= CHANNELS {
+            return;
+        }
+        self.muted[channel] = muted;
+    }
+
+    pub fn set_solo(&mut self, channel: usize, soloed: bool) {
+        if channel >= CHANNELS {
+            return;
+        }
+        self.soloed[channel] = soloed;
+    }
+
+    /// The last SCOPE_LENGTH samples of a channel's DAC output, oldest first.
+    /// Empty for channels past CHANNELS
+    pub fn scope(&self, channel: usize) -> Vec<f32> {
+        if channel >= CHANNELS {
+            return Vec::new();
+        }
+        let scope: &[f32; SCOPE_LENGTH] = &self.scope[channel];
+        let mut samples: Vec<f32> = scope[self.scope_pos..].to_vec();
+        samples.extend_from_slice(&scope[..self.scope_pos]);
+        return samples;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    fn high_pass(&mut self, side: usize, sample: f32) -> f32 {
+        if !self.power {
+            return 0.0;
+        }
+        let out: f32 = sample - self.capacitor[side];
+        self.capacitor[side] = sample - out * self.charge;
+        return out;
+    }
+
+    /// Interleaved stereo samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        return std::mem::take(&mut self.samples);
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if (0xFF30..=0xFF3F).contains(&addr) {
+            self.ch3.ram[(addr - 0xFF30) as usize] = data;
+            return;
+        }
+        if addr == 0xFF26 {
+            self.write_nr52(data);
+            return;
+        }
+        if !self.power {
+            // On DMG the length counters stay writable while powered off
+            match addr {
+                0xFF11 => self.ch1.length.load(data & 0x3F),
+                0xFF16 => self.ch2.length.load(data & 0x3F),
+                0xFF1B => self.ch3.length.load(data),
+                0xFF20 => self.ch4.length.load(data & 0x3F),
+                _ => {},
+            }
+            return;
+        }
+        match addr {
+            0xFF10 => self.ch1.sweep.as_mut().map_or((), |sweep| sweep.write(data)),
+            0xFF11 => {
+                self.ch1.duty = data >> 6;
+                self.ch1.length.load(data & 0x3F);
+            },
+            0xFF12 => {
+                self.ch1.envelope.write(data);
+                if !self.ch1.envelope.dac_on() {
+                    self.ch1.on = false;
+                }
+            },
+            0xFF13 => self.ch1.frequency = (self.ch1.frequency & 0x700) | data as u16,
+            0xFF14 => {
+                self.ch1.frequency = (self.ch1.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.ch1.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.ch1.trigger();
+                }
+            },
+            0xFF16 => {
+                self.ch2.duty = data >> 6;
+                self.ch2.length.load(data & 0x3F);
+            },
+            0xFF17 => {
+                self.ch2.envelope.write(data);
+                if !self.ch2.envelope.dac_on() {
+                    self.ch2.on = false;
+                }
+            },
+            0xFF18 => self.ch2.frequency = (self.ch2.frequency & 0x700) | data as u16,
+            0xFF19 => {
+                self.ch2.frequency = (self.ch2.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.ch2.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.ch2.trigger();
+                }
+            },
+            0xFF1A => {
+                self.ch3.dac = data & 0x80 != 0;
+                if !self.ch3.dac {
+                    self.ch3.on = false;
+                }
+            },
+            0xFF1B => self.ch3.length.load(data),
+            0xFF1C => self.ch3.volume = (data >> 5) & 0x03,
+            0xFF1D => self.ch3.frequency = (self.ch3.frequency & 0x700) | data as u16,
+            0xFF1E => {
+                self.ch3.frequency = (self.ch3.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.ch3.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.ch3.trigger();
+                }
+            },
+            0xFF20 => self.ch4.length.load(data & 0x3F),
+            0xFF21 => {
+                self.ch4.envelope.write(data);
+                if !self.ch4.envelope.dac_on() {
+                    self.ch4.on = false;
+                }
+            },
+            0xFF22 => self.ch4.write_nr43(data),
+            0xFF23 => {
+                self.ch4.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.ch4.trigger();
+                }
+            },
+            0xFF24 => self.nr50 = data,
+            0xFF25 => self.nr51 = data,
+            _ => {},
+        }
+    }
+
+    fn write_nr52(&mut self, data: u8) {
+        let power: bool = data & 0x80 != 0;
+        if self.power && !power {
+            // Powering off clears every register, wave RAM and (on DMG) the length counters survive
+            let lengths: [u16; 4] = [self.ch1.length.counter, self.ch2.length.counter, self.ch3.length.counter, self.ch4.length.counter];
+            let ram: [u8; 16] = self.ch3.ram;
+            self.ch1 = Pulse::new(true);
+            self.ch2 = Pulse::new(false);
+            self.ch3 = Wave::new();
+            self.ch4 = Noise::new();
+            self.ch1.length.counter = lengths[0];
+            self.ch2.length.counter = lengths[1];
+            self.ch3.length.counter = lengths[2];
+            self.ch4.length.counter = lengths[3];
+            self.ch3.ram = ram;
+            self.nr50 = 0;
+            self.nr51 = 0;
+        }
+        else if !self.power && power {
+            self.step = 0;
+        }
+        self.power = power;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        if (0xFF30..=0xFF3F).contains(&addr) {
+            return self.ch3.ram[(addr - 0xFF30) as usize];
+        }
+        let value: u8 = match addr {
+            0xFF10 => self.ch1.sweep.as_ref().map_or(0, |sweep| sweep.read()),
+            0xFF11 => self.ch1.duty << 6,
+            0xFF12 => self.ch1.envelope.read(),
+            0xFF14 => if self.ch1.length.enabled {0x40} else {0x00},
+            0xFF16 => self.ch2.duty << 6,
+            0xFF17 => self.ch2.envelope.read(),
+            0xFF19 => if self.ch2.length.enabled {0x40} else {0x00},
+            0xFF1A => if self.ch3.dac {0x80} else {0x00},
+            0xFF1C => self.ch3.volume << 5,
+            0xFF1E => if self.ch3.length.enabled {0x40} else {0x00},
+            0xFF21 => self.ch4.envelope.read(),
+            0xFF22 => self.ch4.read_nr43(),
+            0xFF23 => if self.ch4.length.enabled {0x40} else {0x00},
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                let power: u8 = if self.power {0x80} else {0x00};
+                let channels: [bool; 4] = [self.ch1.on, self.ch2.on, self.ch3.on, self.ch4.on];
+                let mut status: u8 = 0;
+                for (i, on) in channels.iter().enumerate() {
+                    if *on {
+                        status |= 1 << i;
+                    }
+                }
+                power | status
+            },
+            _ => 0x00,
+        };
+        return match addr {
+            0xFF10..=0xFF26 => value | READ_MASK[(addr - 0xFF10) as usize],
+            _ => 0xFF,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// NR52 channel bits
+    fn channels_on(apu: &Apu) -> u8 {
+        return apu.read(0xFF26) & 0x0F;
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        // Period 1, shift 1, going up: 0x700 + 0x380 overflows as soon as it's triggered
+        let mut apu: Apu = Apu::new(48000);
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+        assert_eq!(channels_on(&apu) & 0x01, 0);
+
+        // 0x500 + 0x280 fits, the check after the first sweep step doesn't
+        apu.write(0xFF14, 0x85);
+        assert_eq!(channels_on(&apu) & 0x01, 0x01);
+        apu.frame_step();
+        apu.frame_step();
+        assert_eq!(channels_on(&apu) & 0x01, 0x01);
+        apu.frame_step();
+        assert_eq!(apu.ch1.frequency, 0x780);
+        assert_eq!(channels_on(&apu) & 0x01, 0);
+    }
+
+    #[test]
+    fn length_counter_expires() {
+        // Length 2 on channel 2, clocked on every even step
+        let mut apu: Apu = Apu::new(48000);
+        apu.write(0xFF16, 0x3E);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0xC0);
+        assert_eq!(channels_on(&apu) & 0x02, 0x02);
+        apu.frame_step();
+        apu.frame_step();
+        assert_eq!(channels_on(&apu) & 0x02, 0x02);
+        apu.frame_step();
+        assert_eq!(channels_on(&apu) & 0x02, 0);
+
+        // Without the length enable bit it plays on
+        apu.write(0xFF16, 0x3F);
+        apu.write(0xFF19, 0x80);
+        for _ in 0..16 {
+            apu.frame_step();
+        }
+        assert_eq!(channels_on(&apu) & 0x02, 0x02);
+    }
+
+    #[test]
+    fn envelope_steps_on_step_7() {
+        let mut apu: Apu = Apu::new(48000);
+        // Volume 15 going down every step, channel 2
+        apu.write(0xFF17, 0xF1);
+        apu.write(0xFF19, 0x80);
+        for _ in 0..7 {
+            apu.frame_step();
+        }
+        assert_eq!(apu.ch2.envelope.volume, 15);
+        apu.frame_step();
+        assert_eq!(apu.ch2.envelope.volume, 14);
+        for _ in 0..8 {
+            apu.frame_step();
+        }
+        assert_eq!(apu.ch2.envelope.volume, 13);
+
+        // Volume 14 going up every second step stops at 15, channel 4
+        apu.write(0xFF21, 0xEA);
+        apu.write(0xFF23, 0x80);
+        for _ in 0..8 * 6 {
+            apu.frame_step();
+        }
+        assert_eq!(apu.ch4.envelope.volume, 15);
+    }
+
+    #[test]
+    fn wave_volume_shift() {
+        let mut apu: Apu = Apu::new(48000);
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1E, 0x80);
+        apu.ch3.sample = 0x0C;
+        // NR32 bits 5-6: mute, 100%, 50%, 25%
+        let levels: [u8; 4] = [0, 12, 6, 3];
+        for (volume, level) in levels.iter().enumerate() {
+            apu.write(0xFF1C, (volume as u8) << 5);
+            assert_eq!(apu.ch3.output(), *level);
+        }
+        // The DAC going off stops the channel
+        apu.write(0xFF1A, 0x00);
+        assert_eq!(channels_on(&apu) & 0x04, 0);
+    }
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu: Apu = Apu::new(48000);
+        apu.write(0xFF26, 0x00);
+        apu.write(0xFF26, 0x80);
+        for addr in 0xFF10..=0xFF25 {
+            assert_eq!(apu.read(addr), READ_MASK[(addr - 0xFF10) as usize], "{:04X}", addr);
+        }
+        assert_eq!(apu.read(0xFF26), 0xF0);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+        assert_eq!(apu.read(0xFF2F), 0xFF);
+        // Only the duty comes back from NR11, only the length enable from NR14
+        apu.write(0xFF11, 0x80);
+        assert_eq!(apu.read(0xFF11), 0xBF);
+        apu.write(0xFF13, 0x12);
+        assert_eq!(apu.read(0xFF13), 0xFF);
+        apu.write(0xFF14, 0x47);
+        assert_eq!(apu.read(0xFF14), 0xFF);
+        apu.write(0xFF1C, 0x40);
+        assert_eq!(apu.read(0xFF1C), 0xDF);
+        apu.write(0xFF10, 0x7F);
+        assert_eq!(apu.read(0xFF10), 0xFF);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu: Apu = Apu::new(48000);
+        apu.write(0xFF12, 0xF3);
+        apu.write(0xFF1C, 0x20);
+        apu.write(0xFF22, 0x55);
+        apu.write(0xFF30, 0x12);
+        apu.write(0xFF16, 0x3E);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x80);
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF26), 0x70);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF25), 0x00);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.read(0xFF1C), 0x9F);
+        assert_eq!(apu.read(0xFF22), 0x00);
+        // Writes are ignored while off, except to wave RAM
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF31, 0x34);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+        assert_eq!(apu.read(0xFF31), 0x34);
+
+        // Back on, the registers stay cleared but the length counter survived
+        apu.write(0xFF26, 0x80);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.ch2.length.counter, 2);
+    }
+
+    #[test]
+    fn out_of_range_channels_are_ignored() {
+        let mut apu: Apu = Apu::new(48000);
+        apu.set_muted(CHANNELS, true);
+        apu.set_solo(CHANNELS, true);
+        assert!(!apu.muted.contains(&true));
+        assert!(!apu.soloed.contains(&true));
+        assert!(apu.scope(CHANNELS).is_empty());
+        assert_eq!(apu.scope(CHANNELS - 1).len(), SCOPE_LENGTH);
+    }
+}
@@ -0,0 +1,129 @@
+////////////////
+/// 
+/// audio.rs
+/// 
+/// Sources:
+/// https://docs.rs/cpal/0.15.3/cpal/ - audio output
+/// 
+/// Plays APU samples through the default output device. The emulator pushes interleaved
+/// stereo samples into a queue once per frame and the device callback pulls them out,
+/// mapping them onto however many channels the device has. When the queue runs dry the
+/// device gets silence, when it grows past MAX_LATENCY the oldest samples are dropped
+/// so sound never lags far behind the picture.
+/// 
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Most audio held in the queue, in seconds
+const MAX_LATENCY: f32 = 0.2;
+
+/*
+ * stream: the running output stream, playback stops when it's dropped
+ * queue: interleaved stereo samples waiting to be played
+ * sample_rate: device samples per second
+ */
+pub struct Audio {
+    _stream: Stream,
+    queue: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
+
+impl Audio {
+    /// Opens the default output device, None if there is none or it can't be started
+    pub fn new() -> Option<Self> {
+        let host: cpal::Host = cpal::default_host();
+        let device: cpal::Device = host.default_output_device()?;
+        let supported: cpal::SupportedStreamConfig = match device.default_output_config() {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("No usable audio output config: {}", err);
+                return None;
+            }
+        };
+        let format: SampleFormat = supported.sample_format();
+        let config: StreamConfig = supported.into();
+        let queue: Arc<Mutex<VecDeque<f32>>> = Arc::new(Mutex::new(VecDeque::new()));
+        let stream: Result<Stream, cpal::BuildStreamError> = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+            SampleFormat::I32 => build_stream::<i32>(&device, &config, queue.clone()),
+            format => {
+                eprintln!("Unsupported audio sample format {}", format);
+                return None;
+            }
+        };
+        let stream: Stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to open audio stream: {}", err);
+                return None;
+            }
+        };
+        if let Err(err) = stream.play() {
+            eprintln!("Failed to start audio stream: {}", err);
+            return None;
+        }
+        Some(Self {
+            _stream: stream,
+            queue,
+            sample_rate: config.sample_rate.0,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.sample_rate;
+    }
+
+    /// Queue interleaved stereo samples for playback
+    pub fn queue(&self, samples: &[f32]) {
+        let mut queue = match self.queue.lock() {
+            Ok(queue) => queue,
+            Err(_) => return,
+        };
+        queue.extend(samples);
+        let max: usize = (self.sample_rate as f32 * MAX_LATENCY) as usize * 2;
+        if queue.len() > max {
+            let excess: usize = queue.len() - max;
+            queue.drain(..excess);
+        }
+    }
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &StreamConfig, queue: Arc<Mutex<VecDeque<f32>>>) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels: usize = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            // A panic elsewhere poisons the queue, play silence rather than panic in the callback
+            let mut queue = match queue.lock() {
+                Ok(queue) => queue,
+                Err(_) => {
+                    data.fill(T::EQUILIBRIUM);
+                    return;
+                }
+            };
+            for frame in data.chunks_mut(channels) {
+                let left: f32 = queue.pop_front().unwrap_or(0.0);
+                let right: f32 = queue.pop_front().unwrap_or(0.0);
+                for (i, sample) in frame.iter_mut().enumerate() {
+                    // Mono devices get both sides, anything past stereo gets silence
+                    let value: f32 = match (channels, i) {
+                        (1, _) => (left + right) / 2.0,
+                        (_, 0) => left,
+                        (_, 1) => right,
+                        _ => 0.0,
+                    };
+                    *sample = T::from_sample(value);
+                }
+            }
+        },
+        |err| eprintln!("Audio stream error: {}", err),
+        None,
+    )
+}
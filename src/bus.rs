@@ -20,52 +20,140 @@
 /// 0xFF00 - 0xFF7F : I/O Registers
 /// 0xFF80 - 0xFFFE : Zero Page
 /// 
-use crate::{cart::Cart, io::IO, ppu::GPU};
+/// OAM DMA
+/// Writing XX to 0xFF46 copies 0xXX00 - 0xXX9F into OAM, one byte per machine cycle
+/// for 160 cycles after a 1 cycle start-up delay. While it runs the DMA owns the bus it
+/// reads from, so the CPU sees the byte being transferred instead of memory on that bus,
+/// and OAM itself reads back 0xFF. HRAM and the I/O registers stay usable, which is why
+/// games run the transfer from a small routine copied into HRAM.
+///     external bus - 0x0000 - 0x7FFF, 0xA000 - 0xFDFF
+///     video bus    - 0x8000 - 0x9FFF
+/// 
+/// Unusable region 0xFEA0 - 0xFEFF
+/// Reads 0xFF while OAM is blocked, otherwise it depends on the hardware revision:
+///     DMG, MGB, SGB    - reads 0x00, writes are ignored
+///     CGB 0 - C        - 24 bytes of extra RAM, address bits 3-4 are ignored
+///     CGB D            - extra RAM, 0xFEC0 - 0xFEFF all land in its last 16 bytes
+///     CGB E, AGB       - reads the high nibble of the address twice (0xFEAx reads 0xAA)
+/// https://gbdev.io/pandocs/Memory_Map.html#fea0feff-range
+/// 
+use crate::{cart::Cart, event::Event, interrupt::{Interrupt, Interrupts}, io::IO, joypad::Button, ppu::GPU};
+use std::path::Path;
 
 const WRAMSIZE: usize = 0x2000;
 const HRAMSIZE: usize = 0x80;
 const VRAM_BEGIN: usize = 0x8000;
+const OAM_BEGIN: u16 = 0xFE00;
+const OAM_SIZE: u8 = 0xA0;
+const UNUSABLE_BEGIN: u16 = 0xFEA0;
+const UNUSABLE_SIZE: usize = 0x60;
+
+/// Hardware revision, for the few places where revisions behave differently
+#[derive(Clone, Copy, PartialEq)]
+pub enum Model {
+    /// DMG, MGB, SGB and SGB2
+    Dmg,
+    /// CGB revisions 0 through C
+    CgbC,
+    /// CGB revision D
+    CgbD,
+    /// CGB revision E, AGB, AGS and GBP
+    CgbE,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dmg" => Some(Model::Dmg),
+            "cgb-c" => Some(Model::CgbC),
+            "cgb-d" => Some(Model::CgbD),
+            "cgb-e" => Some(Model::CgbE),
+            _ => None,
+        }
+    }
+}
+
+/*
+ * reg: last value written to 0xFF46
+ * source: start address of the transfer
+ * index: next OAM byte to copy
+ * delay: machine cycles until the (re)started transfer copies its first byte
+ * active: a transfer is pending or running
+ * blocking: bytes are being copied, so the source bus is taken
+ * value: last byte copied, what the CPU sees when reading the blocked bus
+ */
+struct Dma {
+    reg: u8,
+    source: u16,
+    index: u8,
+    delay: u8,
+    active: bool,
+    blocking: bool,
+    value: u8,
+}
+
+impl Dma {
+    fn new() -> Self {
+        Self { reg: 0xFF, source: 0, index: 0, delay: 0, active: false, blocking: false, value: 0xFF }
+    }
+}
+
 pub struct Bus {
     wram: [u8; WRAMSIZE],
     hram: [u8; HRAMSIZE],
     cart: Cart,
     io: IO,
-    // ie_mirror: u8, //maybe use interrupts in bus
-    gpu: GPU
+    interrupts: Interrupts,
+    gpu: GPU,
+    dma: Dma,
+    model: Model,
+    unusable: [u8; UNUSABLE_SIZE],
+    double_speed: bool,
+    speed_armed: bool,
 }
 
 impl Bus {
-    pub fn new(p_cart: Cart, p_io: IO, p_gpu: GPU)-> Self{
+    pub fn new(p_cart: Cart, p_io: IO, p_gpu: GPU, p_model: Model)-> Self{
         Self {
             wram: [0; WRAMSIZE],
             hram: [0; HRAMSIZE],
             cart: p_cart,
             io: p_io,
-            // ie_mirror: 0x0,
+            interrupts: Interrupts::new(),
             gpu: p_gpu,
+            dma: Dma::new(),
+            model: p_model,
+            unusable: [0; UNUSABLE_SIZE],
+            double_speed: false,
+            speed_armed: false,
         }
     }
+
+    /// Advance everything on the bus by one machine cycle
+    pub fn tick(&mut self){
+        self.dma_tick();
+        self.io.tick(&mut self.interrupts);
+        self.gpu.tick(&mut self.interrupts);
+    }
+
     pub fn write(&mut self, addr: u16, data: u8){
+        if self.dma_conflict(addr) {
+            return;
+        }
         match addr {
-            // 0x0000..0x8000 => todo!("Write to Cart"),
-            // 0x8000..0xA000 => todo!("Char Map Data"),
-            // 0xA000..0xC000 => todo!("Catridge RAM"),
-            // 0xC000..0xE000 => todo!("WRAM write"),
-            // 0xE000..0xFE00 => todo!("echo ram"),
-            // 0xFE00..0xFEA0 => todo!("OAM"),
-            // 0xFEA0..0xFF00 => println!("Map to unusable memory"),
-            // 0xFF00..0xFF80 => todo!("io write"),
-            // 0xFFFF => todo!("set ie reg"),
-            // _ => println!("Out of bounds memory read.")
             0x0000..=0x7FFF => self.cart.write(addr, data),
             0x8000..=0x9FFF => self.gpu.write(addr - (VRAM_BEGIN as u16), data),
             0xA000..=0xBFFF => self.cart.write(addr, data),
             0xC000..=0xDFFF => self.wram_write(addr, data),
-            0xE000..=0xFDFF => return,
-            0xFE00..=0xFE9F => println!("OAM-TODO"),
-            0xFEA0..=0xFEFF => println!("Map to unusable memory"),
+            0xE000..=0xFDFF => self.wram_write(addr - 0x2000, data),
+            0xFE00..=0xFE9F => self.gpu.write_oam(addr - OAM_BEGIN, data),
+            0xFEA0..=0xFEFF => self.unusable_write(addr, data),
+            0xFF0F => self.interrupts.write_if(data),
+            0xFF46 => self.dma_start(data),
+            0xFF4D => self.key1_write(data),
+            0xFF40..=0xFF4B => self.gpu.write_reg(addr, data, &mut self.interrupts),
             0xFF00..=0xFF7F => self.io_write(addr, data),
-            0xFFFF => self.ie_mirror = data,
+            0xFFFF => self.interrupts.write_ie(data),
             _ => self.hram_write(addr, data)
         }
     }
@@ -73,20 +161,143 @@ impl Bus {
      * Read from the bus
      */
     pub fn read(&mut self, addr: u16) -> u8{
+        if self.dma_conflict(addr) {
+            return if (0xFE00..=0xFE9F).contains(&addr) {0xFF} else {self.dma.value};
+        }
+        return self.read_raw(addr);
+    }
+
+    /// Read without the DMA bus conflict, used by the DMA itself
+    fn read_raw(&mut self, addr: u16) -> u8{
         match addr {
             0x0000..=0x7FFF => self.cart.read(addr),
             0x8000..=0x9FFF => self.gpu.read_vram(addr - (VRAM_BEGIN as u16)),
             0xA000..=0xBFFF => self.cart.read(addr),
             0xC000..=0xDFFF => self.wram_read(addr),
-            0xE000..=0xFDFF => return 0,
-            0xFE00..=0xFE9F => {println!("Todo-OAM"); 0},
-            0xFEA0..=0xFEFF => panic!("Map to unusable memory"),
+            0xE000..=0xFDFF => self.wram_read(addr - 0x2000),
+            0xFE00..=0xFE9F => self.gpu.read_oam(addr - OAM_BEGIN),
+            0xFEA0..=0xFEFF => self.unusable_read(addr),
+            0xFF0F => self.interrupts.read_if(),
+            0xFF46 => self.dma.reg,
+            0xFF4D => self.key1_read(),
+            0xFF40..=0xFF4B => self.gpu.read_reg(addr),
             0xFF00..=0xFF7F => self.io_read(addr),
-            0xFFFF => self.ie_mirror,
+            0xFFFF => self.interrupts.read_ie(),
             _ => self.hram_read(addr)
         }
     }
 
+    fn dma_start(&mut self, data: u8){
+        self.dma.reg = data;
+        // Sources past 0xDFFF read the echo of WRAM
+        let page: u16 = if data >= 0xE0 {(data as u16) - 0x20} else {data as u16};
+        self.dma.source = page << 8;
+        self.dma.index = 0;
+        self.dma.delay = 1;
+        self.dma.active = true;
+    }
+
+    fn dma_tick(&mut self){
+        if !self.dma.active {
+            return;
+        }
+        if self.dma.delay > 0 {
+            self.dma.delay -= 1;
+            return;
+        }
+        self.dma.blocking = true;
+        let val: u8 = self.read_raw(self.dma.source + self.dma.index as u16);
+        self.dma.value = val;
+        self.gpu.write_oam(self.dma.index as u16, val);
+        self.dma.index += 1;
+        if self.dma.index == OAM_SIZE {
+            self.dma.active = false;
+            self.dma.blocking = false;
+        }
+    }
+
+    /// Whether a CPU access to addr is cut off by a running DMA
+    fn dma_conflict(&self, addr: u16) -> bool{
+        if !self.dma.blocking {
+            return false;
+        }
+        let on_video_bus = |a: u16| (0x8000..=0x9FFF).contains(&a);
+        match addr {
+            0xFE00..=0xFE9F => true,
+            0xFEA0..=0xFFFF => false,
+            _ => on_video_bus(addr) == on_video_bus(self.dma.source),
+        }
+    }
+
+    /// Where a CGB 0-D access to the unusable region lands in its extra RAM
+    fn unusable_index(&self, addr: u16) -> Option<usize>{
+        match self.model {
+            Model::CgbC => Some((addr & !0x18) as usize - UNUSABLE_BEGIN as usize),
+            Model::CgbD => {
+                let addr: u16 = if addr >= 0xFEC0 {addr | 0xF0} else {addr};
+                Some((addr - UNUSABLE_BEGIN) as usize)
+            },
+            _ => None,
+        }
+    }
+
+    fn unusable_read(&mut self, addr: u16) -> u8{
+        if self.dma.blocking {
+            return 0xFF;
+        }
+        if self.model == Model::CgbE {
+            let nibble: u8 = ((addr >> 4) & 0xF) as u8;
+            return (nibble << 4) | nibble;
+        }
+        match self.unusable_index(addr) {
+            Some(index) => self.unusable[index],
+            None => 0x00,
+        }
+    }
+
+    fn unusable_write(&mut self, addr: u16, data: u8){
+        if let Some(index) = self.unusable_index(addr) {
+            self.unusable[index] = data;
+        }
+    }
+
+    /// KEY1, only present on CGB: bit 7 current speed, bit 0 switch armed
+    fn key1_read(&self) -> u8{
+        if self.model == Model::Dmg {
+            return 0xFF;
+        }
+        let speed: u8 = if self.double_speed {0x80} else {0x00};
+        return 0x7E | speed | self.speed_armed as u8;
+    }
+
+    fn key1_write(&mut self, data: u8){
+        if self.model != Model::Dmg {
+            self.speed_armed = data & 1 != 0;
+        }
+    }
+
+    /// Executes STOP, DIV is reset either way.
+    /// An armed CGB speed switch toggles double speed instead of stopping.
+    /// Returns whether the system clock stopped
+    pub fn stop(&mut self) -> bool{
+        self.io.reset_div();
+        if self.speed_armed {
+            self.speed_armed = false;
+            self.double_speed = !self.double_speed;
+            return false;
+        }
+        return true;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool){
+        self.io.set_button(button, pressed, &mut self.interrupts);
+    }
+
+    /// Whether a pressed button on a selected line ends STOP mode
+    pub fn stop_wake(&mut self) -> bool{
+        return self.io.joypad_low();
+    }
+
     fn wram_write(&mut self, addr: u16, data: u8){
         let new_addr: u16 = addr - 0xC000;
         if new_addr >= 0x2000{
@@ -114,15 +325,182 @@ impl Bus {
     }
 
     fn io_write(&mut self, addr: u16, val: u8){
-        self.io.write(addr, val);
+        self.io.write(addr, val, &mut self.interrupts);
     }
 
     fn io_read(&mut self, addr: u16)->u8{
         return self.io.read(addr);
     }
-    pub fn get_ie_set(&mut self) -> u8{
-        let ret: u8 =  self.ie_mirror;
-        self.ie_mirror = 0;
-        return ret;
+    pub fn save_cart(&mut self) -> std::io::Result<()> {
+        return self.cart.save();
+    }
+    pub fn take_events(&mut self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.cart.take_events();
+        events.append(&mut self.gpu.take_events());
+        return events;
+    }
+
+    /// Interleaved stereo samples the APU produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        return self.io.take_samples();
+    }
+
+    pub fn start_recording(&mut self, path: &Path, separate: bool) -> std::io::Result<()> {
+        return self.io.start_recording(path, separate);
+    }
+
+    pub fn stop_recording(&mut self) -> std::io::Result<()> {
+        return self.io.stop_recording();
+    }
+
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.io.set_channel_muted(channel, muted);
+    }
+
+    pub fn set_channel_solo(&mut self, channel: usize, soloed: bool) {
+        self.io.set_channel_solo(channel, soloed);
+    }
+
+    pub fn channel_scope(&self, channel: usize) -> Vec<f32> {
+        return self.io.channel_scope(channel);
+    }
+
+    /// The last frame drawn by the PPU, 160x144 shades from 0 (white) to 3 (black)
+    pub fn framebuffer(&self) -> &[u8] {
+        return self.gpu.framebuffer();
+    }
+
+    /// The highest priority interrupt that is both requested and enabled
+    pub fn pending_interrupt(&self) -> Option<Interrupt>{
+        return self.interrupts.highest_pending();
+    }
+    pub fn acknowledge_interrupt(&mut self, it: Interrupt){
+        self.interrupts.acknowledge(it);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::Renderer;
+
+    fn bus_for(model: Model) -> Bus {
+        return Bus::new(Cart::new(), IO::new(48000), GPU::new(Renderer::Scanline), model);
+    }
+
+    /// Fills 0xC000 - 0xC09F with a pattern and starts a DMA from there
+    fn start_dma(bus: &mut Bus) -> Vec<u8> {
+        let source: Vec<u8> = (0..OAM_SIZE).map(|i| i ^ 0x5A).collect();
+        for (i, byte) in source.iter().enumerate() {
+            bus.write(0xC000 + i as u16, *byte);
+        }
+        bus.write(0xFF46, 0xC0);
+        return source;
+    }
+
+    #[test]
+    fn dma_copies_160_bytes_after_a_delay() {
+        let mut bus: Bus = bus_for(Model::Dmg);
+        let source: Vec<u8> = start_dma(&mut bus);
+        assert_eq!(bus.read(0xFF46), 0xC0);
+        // Start-up cycle, nothing copied or blocked yet
+        bus.tick();
+        assert_eq!(bus.read(0xFE00), 0x00);
+        assert_eq!(bus.read(0xC010), source[0x10]);
+        for _ in 0..OAM_SIZE - 1 {
+            bus.tick();
+        }
+        // Still one byte to go
+        assert_eq!(bus.read(0xFE00), 0xFF);
+        assert_eq!(bus.read_raw(0xFE9F), 0x00);
+        bus.tick();
+        for i in 0..OAM_SIZE as u16 {
+            assert_eq!(bus.read(0xFE00 + i), source[i as usize]);
+        }
+    }
+
+    #[test]
+    fn dma_bus_conflict() {
+        let mut bus: Bus = bus_for(Model::Dmg);
+        bus.write(0xFF80, 0x12);
+        bus.write(0x8000, 0x34);
+        let source: Vec<u8> = start_dma(&mut bus);
+        bus.tick();
+        for i in 0..3 {
+            bus.tick();
+            // The external bus reads the byte just copied, OAM reads 0xFF
+            assert_eq!(bus.read(0xD000), source[i]);
+            assert_eq!(bus.read(0x0000), source[i]);
+            assert_eq!(bus.read(0xFE50), 0xFF);
+            // HRAM and the video bus are free
+            assert_eq!(bus.read(0xFF80), 0x12);
+            assert_eq!(bus.read(0x8000), 0x34);
+        }
+        // CPU writes to the blocked bus are lost
+        bus.write(0xD000, 0x99);
+        bus.write(0xFE50, 0x99);
+        for _ in 0..OAM_SIZE {
+            bus.tick();
+        }
+        assert_eq!(bus.read(0xD000), 0x00);
+        assert_eq!(bus.read(0xFE50), source[0x50]);
+
+        // A transfer from VRAM blocks the video bus instead
+        let mut bus: Bus = bus_for(Model::Dmg);
+        bus.write(0xC000, 0x56);
+        bus.write(0x8000, 0x34);
+        bus.write(0xFF46, 0x80);
+        bus.tick();
+        bus.tick();
+        assert_eq!(bus.read(0x9000), 0x34);
+        assert_eq!(bus.read(0xC000), 0x56);
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut bus: Bus = bus_for(Model::Dmg);
+        bus.write(0xC123, 0x11);
+        assert_eq!(bus.read(0xE123), 0x11);
+        bus.write(0xF456, 0x22);
+        assert_eq!(bus.read(0xD456), 0x22);
+        bus.write(0xFDFF, 0x33);
+        assert_eq!(bus.read(0xDDFF), 0x33);
+    }
+
+    #[test]
+    fn unusable_region_per_model() {
+        let mut dmg: Bus = bus_for(Model::Dmg);
+        dmg.write(0xFEA0, 0x12);
+        assert_eq!(dmg.read(0xFEA0), 0x00);
+        assert_eq!(dmg.read(0xFEFF), 0x00);
+
+        // Address bits 3-4 are ignored
+        let mut cgb_c: Bus = bus_for(Model::CgbC);
+        cgb_c.write(0xFEA1, 0x12);
+        assert_eq!(cgb_c.read(0xFEA1), 0x12);
+        assert_eq!(cgb_c.read(0xFEB9), 0x12);
+        cgb_c.write(0xFEC2, 0x34);
+        assert_eq!(cgb_c.read(0xFED2), 0x34);
+        assert_eq!(cgb_c.read(0xFEA2), 0x00);
+
+        // 0xFEC0 - 0xFEFF share the last 16 bytes
+        let mut cgb_d: Bus = bus_for(Model::CgbD);
+        cgb_d.write(0xFEA5, 0x12);
+        cgb_d.write(0xFEC5, 0x34);
+        assert_eq!(cgb_d.read(0xFEA5), 0x12);
+        assert_eq!(cgb_d.read(0xFED5), 0x34);
+        assert_eq!(cgb_d.read(0xFEF5), 0x34);
+        assert_eq!(cgb_d.read(0xFEB5), 0x00);
+
+        let mut cgb_e: Bus = bus_for(Model::CgbE);
+        cgb_e.write(0xFEA3, 0x12);
+        assert_eq!(cgb_e.read(0xFEA3), 0xAA);
+        assert_eq!(cgb_e.read(0xFEC1), 0xCC);
+        assert_eq!(cgb_e.read(0xFEFF), 0xFF);
+
+        // Blocked along with OAM during a DMA
+        cgb_c.write(0xFF46, 0xC0);
+        cgb_c.tick();
+        cgb_c.tick();
+        assert_eq!(cgb_c.read(0xFEA1), 0xFF);
+    }
+}
@@ -4,94 +4,1356 @@
 /// 
 /// Sources:
 /// https://gbdev.io/pandocs/The_Cartridge_Header.html - Locating data in the header
+/// https://gbdev.io/pandocs/MBCs.html - Memory bank controllers
+/// https://gbdev.io/pandocs/MBC1.html - MBC1
+/// https://gbdev.io/pandocs/MBC3.html - MBC3 and the real time clock
+/// https://gbdev.io/pandocs/MBC5.html - MBC5
+/// https://gbdev.io/pandocs/MBC2.html - MBC2
+/// https://gbdev.io/pandocs/MMM01.html - MMM01
+/// https://gbdev.io/pandocs/HuC1.html - HuC1
+/// https://gbdev.io/pandocs/HuC3.html - HuC3
+/// https://bgb.bircd.org/rtcsave.html - RTC save format shared by BGB and VBA-M
 /// 
-/// 
-
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::event::Event;
+use crate::header::{CartHeader, CartType, Mapper};
+use crate::patch::{self, PatchError};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const HEADER_END: usize = 0x0150;
+const MBC2_RAM_SIZE: usize = 0x200;
+/// Live registers, latched registers and a timestamp appended after the RAM in .sav files
+const RTC_SAVE_SIZE: usize = 48;
+/// HuC3 minutes, days and seconds, RTC memory and a timestamp appended after the RAM in .sav files
+const HUC3_SAVE_SIZE: usize = 12 + 0x100 + 8;
+
+/// MBC1
+/// 0x0000 - 0x1FFF : RAM enable (0x0A in the lower nibble enables)
+/// 0x2000 - 0x3FFF : ROM bank number, lower 5 bits (0 is treated as 1)
+/// 0x4000 - 0x5FFF : RAM bank number or upper 2 bits of the ROM bank number
+/// 0x6000 - 0x7FFF : Banking mode select
+///     mode 0 - 0x0000-0x3FFF is bank 0 and RAM is locked to bank 0
+///     mode 1 - the upper bits also select the bank at 0x0000-0x3FFF and the RAM bank
+struct Mbc1 {
+  ram_enabled: bool,
+  bank1: u8,
+  bank2: u8,
+  mode: u8,
+}
+
+impl Mbc1 {
+  fn new() -> Self {
+    Self { ram_enabled: false, bank1: 1, bank2: 0, mode: 0 }
+  }
+
+  fn rom_addr(&self, addr: u16) -> usize {
+    let bank: usize = match addr {
+      0x0000..=0x3FFF => if self.mode == 1 {(self.bank2 as usize) << 5} else {0},
+      _ => ((self.bank2 as usize) << 5) | self.bank1 as usize,
+    };
+    return bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+  }
+
+  fn ram_addr(&self, addr: u16) -> usize {
+    let bank: usize = if self.mode == 1 {self.bank2 as usize} else {0};
+    return bank * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1));
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x7FFF => rom[self.rom_addr(addr) % rom.len()],
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled || ram.is_empty() {
+          return 0xFF;
+        }
+        return ram[self.ram_addr(addr) % ram.len()];
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => self.ram_enabled = (data & 0x0F) == 0x0A,
+      0x2000..=0x3FFF => {
+        self.bank1 = data & 0x1F;
+        if self.bank1 == 0 {
+          self.bank1 = 1;
+        }
+      },
+      0x4000..=0x5FFF => self.bank2 = data & 0b11,
+      0x6000..=0x7FFF => self.mode = data & 1,
+      0xA000..=0xBFFF => {
+        if self.ram_enabled && !ram.is_empty() {
+          let len: usize = ram.len();
+          ram[self.ram_addr(addr) % len] = data;
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+/// MBC3 real time clock
+/// 0x08 : Seconds   0-59
+/// 0x09 : Minutes   0-59
+/// 0x0A : Hours     0-23
+/// 0x0B : Day counter, lower 8 bits
+/// 0x0C : Day counter upper bit (bit 0), halt (bit 6), day counter carry (bit 7)
+///
+/// The clock runs off host wall-clock time, so it keeps counting while the emulator is closed
+/// as long as `last_time` is persisted alongside the registers.
+struct Rtc {
+  seconds: u8,
+  minutes: u8,
+  hours: u8,
+  days: u16,
+  halt: bool,
+  carry: bool,
+  latched: [u8; 5],
+  last_time: u64,
+}
+
+impl Rtc {
+  fn new() -> Self {
+    Self {
+      seconds: 0,
+      minutes: 0,
+      hours: 0,
+      days: 0,
+      halt: false,
+      carry: false,
+      latched: [0; 5],
+      last_time: unix_time(),
+    }
+  }
+
+  /// Advance the live registers by the wall-clock time passed since the last update
+  fn update(&mut self) {
+    let now: u64 = unix_time();
+    let elapsed: u64 = now.saturating_sub(self.last_time);
+    self.last_time = now;
+    if self.halt || elapsed == 0 {
+      return;
+    }
+
+    let seconds: u64 = self.seconds as u64 + elapsed;
+    self.seconds = (seconds % 60) as u8;
+    let minutes: u64 = self.minutes as u64 + seconds / 60;
+    self.minutes = (minutes % 60) as u8;
+    let hours: u64 = self.hours as u64 + minutes / 60;
+    self.hours = (hours % 24) as u8;
+    let days: u64 = self.days as u64 + hours / 24;
+    if days > 0x1FF {
+      self.carry = true;
+    }
+    self.days = (days & 0x1FF) as u16;
+  }
+
+  fn get_reg(&self, reg: u8) -> u8 {
+    match reg {
+      0x08 => self.seconds,
+      0x09 => self.minutes,
+      0x0A => self.hours,
+      0x0B => (self.days & 0xFF) as u8,
+      _ => {
+        let day_hi: u8 = ((self.days >> 8) & 1) as u8;
+        let halt: u8 = if self.halt {1 << 6} else {0};
+        let carry: u8 = if self.carry {1 << 7} else {0};
+        day_hi | halt | carry
+      }
+    }
+  }
+
+  /// Copy the live registers into the latched copy the CPU reads from
+  fn latch(&mut self) {
+    self.update();
+    for reg in 0x08..=0x0C {
+      self.latched[(reg - 0x08) as usize] = self.get_reg(reg);
+    }
+  }
+
+  fn read(&self, reg: u8) -> u8 {
+    return self.latched[(reg - 0x08) as usize];
+  }
+
+  /// Live and latched registers as little-endian u32s followed by a 64 bit unix timestamp
+  fn to_save(&mut self) -> Vec<u8> {
+    self.update();
+    let mut data: Vec<u8> = Vec::with_capacity(RTC_SAVE_SIZE);
+    for reg in 0x08..=0x0C {
+      data.extend_from_slice(&(self.get_reg(reg) as u32).to_le_bytes());
+    }
+    for reg in 0..5 {
+      data.extend_from_slice(&(self.latched[reg] as u32).to_le_bytes());
+    }
+    data.extend_from_slice(&self.last_time.to_le_bytes());
+    return data;
+  }
+
+  /// Restore from a save footer, accepting the older 44 byte variant with a 32 bit timestamp
+  fn load_save(&mut self, data: &[u8]) {
+    if data.len() < 44 {
+      return;
+    }
+    let word = |i: usize| -> u32 { u32::from_le_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]) };
+    for reg in 0x08..=0x0C {
+      self.write(reg, word((reg - 0x08) as usize) as u8);
+    }
+    for reg in 0..5 {
+      self.latched[reg] = word(reg + 5) as u8;
+    }
+    self.last_time = if data.len() >= RTC_SAVE_SIZE {
+      let mut time: [u8; 8] = [0; 8];
+      time.copy_from_slice(&data[40..48]);
+      u64::from_le_bytes(time)
+    }
+    else {
+      word(10) as u64
+    };
+    // Catch up on the time that passed while the emulator was closed
+    self.update();
+  }
+
+  fn write(&mut self, reg: u8, data: u8) {
+    self.update();
+    match reg {
+      0x08 => self.seconds = data & 0x3F,
+      0x09 => self.minutes = data & 0x3F,
+      0x0A => self.hours = data & 0x1F,
+      0x0B => self.days = (self.days & 0x100) | data as u16,
+      _ => {
+        self.days = (self.days & 0xFF) | (((data & 1) as u16) << 8);
+        self.halt = (data & (1 << 6)) != 0;
+        self.carry = (data & (1 << 7)) != 0;
+      }
+    }
+    // Writes show up in the latched copy as well
+    self.latched[(reg - 0x08) as usize] = self.get_reg(reg);
+  }
+}
+
+/// MBC3
+/// 0x0000 - 0x1FFF : RAM and timer enable (0x0A in the lower nibble enables)
+/// 0x2000 - 0x3FFF : ROM bank number, 7 bits (0 is treated as 1)
+/// 0x4000 - 0x5FFF : RAM bank number (0x00-0x03) or RTC register select (0x08-0x0C)
+/// 0x6000 - 0x7FFF : Latch clock data, writing 0x00 then 0x01 latches the RTC
+struct Mbc3 {
+  ram_enabled: bool,
+  rom_bank: u8,
+  ram_bank: u8,
+  latch_prev: u8,
+  rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+  fn new(has_rtc: bool) -> Self {
+    Self {
+      ram_enabled: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      latch_prev: 0xFF,
+      rtc: if has_rtc {Some(Rtc::new())} else {None},
+    }
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x3FFF => rom[addr as usize],
+      0x4000..=0x7FFF => {
+        let offset: usize = self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - 0x4000);
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled {
+          return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+          (0x00..=0x03, _) if !ram.is_empty() => {
+            let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+            return ram[offset % ram.len()];
+          },
+          (0x08..=0x0C, Some(rtc)) => rtc.read(self.ram_bank),
+          _ => 0xFF,
+        }
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => self.ram_enabled = (data & 0x0F) == 0x0A,
+      0x2000..=0x3FFF => {
+        self.rom_bank = data & 0x7F;
+        if self.rom_bank == 0 {
+          self.rom_bank = 1;
+        }
+      },
+      0x4000..=0x5FFF => self.ram_bank = data,
+      0x6000..=0x7FFF => {
+        if self.latch_prev == 0x00 && data == 0x01 {
+          if let Some(rtc) = &mut self.rtc {
+            rtc.latch();
+          }
+        }
+        self.latch_prev = data;
+      },
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled {
+          return;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+          (0x00..=0x03, _) if !ram.is_empty() => {
+            let len: usize = ram.len();
+            let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+            ram[offset % len] = data;
+          },
+          (0x08..=0x0C, Some(rtc)) => rtc.write(self.ram_bank, data),
+          _ => {}
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+/// MBC5
+/// 0x0000 - 0x1FFF : RAM enable (only 0x0A enables)
+/// 0x2000 - 0x2FFF : ROM bank number, lower 8 bits (bank 0 is selectable)
+/// 0x3000 - 0x3FFF : ROM bank number, bit 8
+/// 0x4000 - 0x5FFF : RAM bank number (0x00-0x0F)
+///     on rumble carts bit 3 drives the rumble motor and only bits 0-2 select the RAM bank
+struct Mbc5 {
+  ram_enabled: bool,
+  rom_bank: u16,
+  ram_bank: u8,
+  has_rumble: bool,
+  rumble: bool,
+}
+
+impl Mbc5 {
+  fn new(has_rumble: bool) -> Self {
+    Self { ram_enabled: false, rom_bank: 1, ram_bank: 0, has_rumble, rumble: false }
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x3FFF => rom[addr as usize],
+      0x4000..=0x7FFF => {
+        let offset: usize = self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - 0x4000);
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled || ram.is_empty() {
+          return 0xFF;
+        }
+        let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        return ram[offset % ram.len()];
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+      0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+      0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (((data & 1) as u16) << 8),
+      0x4000..=0x5FFF => {
+        if self.has_rumble {
+          self.rumble = (data & 0b1000) != 0;
+          self.ram_bank = data & 0b0111;
+        }
+        else {
+          self.ram_bank = data & 0x0F;
+        }
+      },
+      0xA000..=0xBFFF => {
+        if self.ram_enabled && !ram.is_empty() {
+          let len: usize = ram.len();
+          let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+          ram[offset % len] = data;
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+/// MBC2
+/// 0x0000 - 0x3FFF : Register select by address bit 8
+///     bit 8 clear - RAM enable (0x0A in the lower nibble enables)
+///     bit 8 set   - ROM bank number, 4 bits (0 is treated as 1)
+/// 0xA000 - 0xA1FF : 512 x 4 bit built-in RAM, echoed through 0xBFFF
+struct Mbc2 {
+  ram_enabled: bool,
+  rom_bank: u8,
+}
+
+impl Mbc2 {
+  fn new() -> Self {
+    Self { ram_enabled: false, rom_bank: 1 }
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x3FFF => rom[addr as usize],
+      0x4000..=0x7FFF => {
+        let offset: usize = self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - 0x4000);
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled {
+          return 0xFF;
+        }
+        // Only the lower nibble exists, the upper one reads back as set
+        return 0xF0 | ram[addr as usize & (MBC2_RAM_SIZE - 1)];
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x3FFF => {
+        if addr & (1 << 8) == 0 {
+          self.ram_enabled = (data & 0x0F) == 0x0A;
+        }
+        else {
+          self.rom_bank = data & 0x0F;
+          if self.rom_bank == 0 {
+            self.rom_bank = 1;
+          }
+        }
+      },
+      0xA000..=0xBFFF => {
+        if self.ram_enabled {
+          ram[addr as usize & (MBC2_RAM_SIZE - 1)] = data & 0x0F;
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+/// MMM01
+/// Starts up "unmapped" with the last 32 KiB of the ROM (the multicart menu) at 0x0000-0x7FFF.
+/// The menu writes the outer bank bits and then sets the map enable bit, which locks the
+/// outer bits and from then on behaves like an MBC1 inside the selected game.
+/// 0x0000 - 0x1FFF : RAM enable, RAM bank bits 2-3 (unmapped only), map enable bit 6 (unmapped only)
+/// 0x2000 - 0x3FFF : ROM bank bits 0-4, ROM bank bits 5-6 (unmapped only)
+/// 0x4000 - 0x5FFF : RAM bank bits 0-1, ROM bank bits 7-8 (unmapped only), mode lock bit 6 (unmapped only)
+/// 0x6000 - 0x7FFF : Banking mode, ROM bank mask bits 2-5 (unmapped only)
+struct Mmm01 {
+  mapped: bool,
+  ram_enabled: bool,
+  rom_bank_lo: u8,
+  rom_bank_mid: u8,
+  rom_bank_hi: u8,
+  rom_mask: u8,
+  ram_bank_lo: u8,
+  ram_bank_hi: u8,
+  mode: u8,
+  mode_locked: bool,
+}
+
+impl Mmm01 {
+  fn new() -> Self {
+    Self {
+      mapped: false,
+      ram_enabled: false,
+      rom_bank_lo: 0,
+      rom_bank_mid: 0,
+      rom_bank_hi: 0,
+      rom_mask: 0,
+      ram_bank_lo: 0,
+      ram_bank_hi: 0,
+      mode: 0,
+      mode_locked: false,
+    }
+  }
+
+  fn rom_bank(&self, addr: u16) -> usize {
+    if !self.mapped {
+      // 0x1FE and 0x1FF wrap to the last two banks of any ROM size
+      return if addr < 0x4000 {0x1FE} else {0x1FF};
+    }
+    let outer: usize = ((self.rom_bank_hi as usize) << 7) | ((self.rom_bank_mid as usize) << 5);
+    // Bits covered by the mask are fixed by the menu and apply to both ROM areas
+    let masked: u8 = self.rom_bank_lo & (self.rom_mask << 1);
+    if addr < 0x4000 {
+      return outer | if self.mode == 1 {masked as usize} else {0};
+    }
+    let mut lo: u8 = self.rom_bank_lo;
+    if lo & !(self.rom_mask << 1) & 0x1F == 0 {
+      lo |= 1;
+    }
+    return outer | lo as usize;
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x7FFF => {
+        let offset: usize = self.rom_bank(addr) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => {
+        if !self.ram_enabled || ram.is_empty() {
+          return 0xFF;
+        }
+        let bank: usize = ((self.ram_bank_hi << 2) | self.ram_bank_lo) as usize;
+        return ram[(bank * RAM_BANK_SIZE + (addr as usize - 0xA000)) % ram.len()];
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => {
+        self.ram_enabled = (data & 0x0F) == 0x0A;
+        if !self.mapped {
+          self.ram_bank_hi = (data >> 4) & 0b11;
+          self.mapped = (data & (1 << 6)) != 0;
+        }
+      },
+      0x2000..=0x3FFF => {
+        if self.mapped {
+          // The bits the menu masked stay as it left them, the game can't leave its slice
+          let mask: u8 = self.rom_mask << 1;
+          self.rom_bank_lo = (self.rom_bank_lo & mask) | (data & 0x1F & !mask);
+        }
+        else {
+          self.rom_bank_lo = data & 0x1F;
+          self.rom_bank_mid = (data >> 5) & 0b11;
+        }
+      },
+      0x4000..=0x5FFF => {
+        self.ram_bank_lo = data & 0b11;
+        if !self.mapped {
+          self.rom_bank_hi = (data >> 4) & 0b11;
+          self.mode_locked = (data & (1 << 6)) != 0;
+        }
+      },
+      0x6000..=0x7FFF => {
+        if !self.mode_locked {
+          self.mode = data & 1;
+        }
+        if !self.mapped {
+          self.rom_mask = (data >> 2) & 0x0F;
+        }
+      },
+      0xA000..=0xBFFF => {
+        if self.ram_enabled && !ram.is_empty() {
+          let len: usize = ram.len();
+          let bank: usize = ((self.ram_bank_hi << 2) | self.ram_bank_lo) as usize;
+          ram[(bank * RAM_BANK_SIZE + (addr as usize - 0xA000)) % len] = data;
+        }
+      },
+      _ => {}
+    }
+  }
+}
+
+/// HuC1
+/// 0x0000 - 0x1FFF : 0x0E maps the IR port to 0xA000-0xBFFF, anything else maps RAM
+/// 0x2000 - 0x3FFF : ROM bank number, 6 bits
+/// 0x4000 - 0x5FFF : RAM bank number, 2 bits
+///
+/// IR port: reads 0xC1 while light is seen and 0xC0 otherwise, bit 0 of a write drives the LED.
+/// There is no second Game Boy to talk to, so no light is ever seen and the LED is ignored.
+struct Huc1 {
+  ir_mode: bool,
+  rom_bank: u8,
+  ram_bank: u8,
+}
+
+impl Huc1 {
+  fn new() -> Self {
+    Self { ir_mode: false, rom_bank: 1, ram_bank: 0 }
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x3FFF => rom[addr as usize],
+      0x4000..=0x7FFF => {
+        let offset: usize = self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - 0x4000);
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => {
+        if self.ir_mode {
+          return 0xC0;
+        }
+        if ram.is_empty() {
+          return 0xFF;
+        }
+        let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        return ram[offset % ram.len()];
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => self.ir_mode = (data & 0x0F) == 0x0E,
+      0x2000..=0x3FFF => self.rom_bank = data & 0x3F,
+      0x4000..=0x5FFF => self.ram_bank = data & 0b11,
+      0xA000..=0xBFFF => {
+        if !self.ir_mode && !ram.is_empty() {
+          let len: usize = ram.len();
+          let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+          ram[offset % len] = data;
+        }
+      },
+      _ => {}
+    }
+  }
+}
 
-use std::str::from_utf8;
+/// HuC3
+/// 0x0000 - 0x1FFF : Selects what 0xA000-0xBFFF maps to
+///     0x00 - RAM, read only        0x0A - RAM, read/write
+///     0x0B - RTC command/argument  0x0C - RTC command response
+///     0x0D - RTC semaphore         0x0E - IR port
+/// 0x2000 - 0x3FFF : ROM bank number, 7 bits
+/// 0x4000 - 0x5FFF : RAM bank number, 2 bits
+///
+/// The RTC is driven by writing a command in bits 4-6 and an argument in bits 0-3:
+///     0x1 - read the nibble at the RTC address into the response, then increment the address
+///     0x3 - write the argument to the RTC address, then increment the address
+///     0x4 - set the low nibble of the RTC address
+///     0x5 - set the high nibble of the RTC address
+///     0x6 - 0x0 copies the clock into RTC memory 0x00-0x05, 0x1 copies it back to the clock
+/// The clock counts minutes (12 bits) and days (12 bits) from host wall-clock time. Like the
+/// MBC3 RTC, it is saved with its RTC memory and a timestamp so it keeps counting while closed.
+struct Huc3 {
+  mode: u8,
+  rom_bank: u8,
+  ram_bank: u8,
+  rtc_memory: [u8; 0x100],
+  rtc_addr: u8,
+  rtc_command: u8,
+  rtc_response: u8,
+  minutes: u16,
+  days: u16,
+  seconds: u64,
+  last_time: u64,
+}
+
+impl Huc3 {
+  fn new() -> Self {
+    Self {
+      mode: 0,
+      rom_bank: 1,
+      ram_bank: 0,
+      rtc_memory: [0; 0x100],
+      rtc_addr: 0,
+      rtc_command: 0,
+      rtc_response: 0,
+      minutes: 0,
+      days: 0,
+      seconds: 0,
+      last_time: unix_time(),
+    }
+  }
+
+  /// Clock as little-endian u32 minutes, days and seconds, RTC memory, then a 64 bit unix timestamp
+  fn to_save(&mut self) -> Vec<u8> {
+    self.update_clock();
+    let mut data: Vec<u8> = Vec::with_capacity(HUC3_SAVE_SIZE);
+    data.extend_from_slice(&(self.minutes as u32).to_le_bytes());
+    data.extend_from_slice(&(self.days as u32).to_le_bytes());
+    data.extend_from_slice(&(self.seconds as u32).to_le_bytes());
+    data.extend_from_slice(&self.rtc_memory);
+    data.extend_from_slice(&self.last_time.to_le_bytes());
+    return data;
+  }
+
+  /// Restore from a save footer written by `to_save`, anything shorter is ignored
+  fn load_save(&mut self, data: &[u8]) {
+    if data.len() < HUC3_SAVE_SIZE {
+      return;
+    }
+    let word = |i: usize| -> u32 { u32::from_le_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]) };
+    self.minutes = (word(0) % (60 * 24)) as u16;
+    self.days = (word(1) & 0xFFF) as u16;
+    self.seconds = (word(2) % 60) as u64;
+    self.rtc_memory.copy_from_slice(&data[12..12 + 0x100]);
+    let mut time: [u8; 8] = [0; 8];
+    time.copy_from_slice(&data[12 + 0x100..HUC3_SAVE_SIZE]);
+    self.last_time = u64::from_le_bytes(time);
+    // Catch up on the time that passed while the emulator was closed
+    self.update_clock();
+  }
+
+  fn update_clock(&mut self) {
+    let now: u64 = unix_time();
+    self.seconds += now.saturating_sub(self.last_time);
+    self.last_time = now;
+
+    let minutes: u64 = self.minutes as u64 + self.seconds / 60;
+    self.seconds %= 60;
+    self.minutes = (minutes % (60 * 24)) as u16;
+    self.days = ((self.days as u64 + minutes / (60 * 24)) & 0xFFF) as u16;
+  }
+
+  fn rtc_command(&mut self, data: u8) {
+    self.rtc_command = (data >> 4) & 0b111;
+    let arg: u8 = data & 0x0F;
+    match self.rtc_command {
+      0x1 => {
+        self.rtc_response = self.rtc_memory[self.rtc_addr as usize];
+        self.rtc_addr = self.rtc_addr.wrapping_add(1);
+      },
+      0x3 => {
+        self.rtc_memory[self.rtc_addr as usize] = arg;
+        self.rtc_addr = self.rtc_addr.wrapping_add(1);
+      },
+      0x4 => self.rtc_addr = (self.rtc_addr & 0xF0) | arg,
+      0x5 => self.rtc_addr = (self.rtc_addr & 0x0F) | (arg << 4),
+      0x6 => {
+        self.update_clock();
+        if arg == 0x0 {
+          for i in 0..3 {
+            self.rtc_memory[i] = ((self.minutes >> (i * 4)) & 0x0F) as u8;
+            self.rtc_memory[i + 3] = ((self.days >> (i * 4)) & 0x0F) as u8;
+          }
+        }
+        else if arg == 0x1 {
+          self.minutes = 0;
+          self.days = 0;
+          for i in 0..3 {
+            self.minutes |= (self.rtc_memory[i] as u16) << (i * 4);
+            self.days |= (self.rtc_memory[i + 3] as u16) << (i * 4);
+          }
+          self.seconds = 0;
+        }
+      },
+      _ => {}
+    }
+  }
+
+  fn read(&mut self, rom: &[u8], ram: &[u8], addr: u16) -> u8 {
+    match addr {
+      0x0000..=0x3FFF => rom[addr as usize],
+      0x4000..=0x7FFF => {
+        let offset: usize = self.rom_bank as usize * ROM_BANK_SIZE + (addr as usize - 0x4000);
+        return rom[offset % rom.len()];
+      },
+      0xA000..=0xBFFF => match self.mode {
+        0x00 | 0x0A => {
+          if ram.is_empty() {
+            return 0xFF;
+          }
+          let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+          return ram[offset % ram.len()];
+        },
+        0x0C => 0x80 | (self.rtc_command << 4) | self.rtc_response,
+        // The RTC always reports it is ready
+        0x0D => 0xFF,
+        // No IR light is ever seen
+        0x0E => 0xC0,
+        _ => 0xFF,
+      },
+      _ => 0xFF,
+    }
+  }
+
+  fn write(&mut self, ram: &mut [u8], addr: u16, data: u8) {
+    match addr {
+      0x0000..=0x1FFF => self.mode = data & 0x0F,
+      0x2000..=0x3FFF => self.rom_bank = data & 0x7F,
+      0x4000..=0x5FFF => self.ram_bank = data & 0b11,
+      0xA000..=0xBFFF => match self.mode {
+        0x0A => {
+          if !ram.is_empty() {
+            let len: usize = ram.len();
+            let offset: usize = self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+            ram[offset % len] = data;
+          }
+        },
+        0x0B => self.rtc_command(data),
+        _ => {}
+      },
+      _ => {}
+    }
+  }
+}
+
+/// Reasons a ROM image can't be turned into a Cart
+#[derive(Debug)]
+pub enum CartError {
+  /// Reading the ROM file failed
+  Io(std::io::Error),
+  /// The zip or gzip archive is corrupt or holds no ROM
+  Archive(String),
+  /// The IPS/BPS/UPS patch couldn't be applied
+  Patch(PatchError),
+  /// The file ends before the end of the header at 0x0150
+  FileTooSmall(usize),
+  /// The header has a ROM or RAM size code that doesn't exist
+  BadHeader(String),
+  /// The cartridge type byte names hardware that isn't emulated
+  UnsupportedMapper(u8),
+  /// The file size doesn't match the ROM size in the header
+  SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CartError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CartError::Io(err) => write!(f, "couldn't read ROM: {}", err),
+      CartError::Archive(reason) => write!(f, "couldn't extract ROM: {}", reason),
+      CartError::Patch(err) => write!(f, "couldn't apply patch: {}", err),
+      CartError::FileTooSmall(size) => write!(f, "file is {} bytes, too small to hold a cartridge header", size),
+      CartError::BadHeader(reason) => write!(f, "bad cartridge header: {}", reason),
+      CartError::UnsupportedMapper(code) => write!(f, "unsupported cartridge type {:#04X}", code),
+      CartError::SizeMismatch { expected, actual } => write!(f, "header says the ROM is {} bytes but the file is {} bytes", expected, actual),
+    }
+  }
+}
+
+impl std::error::Error for CartError {}
+
+impl From<std::io::Error> for CartError {
+  fn from(err: std::io::Error) -> Self {
+    CartError::Io(err)
+  }
+}
+
+impl From<PatchError> for CartError {
+  fn from(err: PatchError) -> Self {
+    CartError::Patch(err)
+  }
+}
+
+/// Memory bank controller selected from the cartridge type byte at 0x0147
+enum Mbc {
+  RomOnly,
+  Mbc1(Mbc1),
+  Mbc3(Mbc3),
+  Mbc5(Mbc5),
+  Mbc2(Mbc2),
+  Mmm01(Mmm01),
+  Huc1(Huc1),
+  Huc3(Huc3),
+}
+
+impl Mbc {
+  fn from_header(header: &CartHeader) -> Result<Self, CartError> {
+    let cart_type: CartType = header.cart_type;
+    let mbc: Mbc = match cart_type.mapper {
+      Mapper::RomOnly => Mbc::RomOnly,
+      Mapper::Mbc1 => Mbc::Mbc1(Mbc1::new()),
+      Mapper::Mbc2 => Mbc::Mbc2(Mbc2::new()),
+      Mapper::Mmm01 => Mbc::Mmm01(Mmm01::new()),
+      Mapper::Mbc3 => Mbc::Mbc3(Mbc3::new(cart_type.timer)),
+      Mapper::Mbc5 => Mbc::Mbc5(Mbc5::new(cart_type.rumble)),
+      Mapper::Huc3 => Mbc::Huc3(Huc3::new()),
+      Mapper::Huc1 => Mbc::Huc1(Huc1::new()),
+      _ => return Err(CartError::UnsupportedMapper(cart_type.code)),
+    };
+    return Ok(mbc);
+  }
+}
 
 pub struct Cart {
   rom: Vec<u8>,
+  ram: Vec<u8>,
+  mbc: Mbc,
+  events: Vec<Event>,
+  save_path: Option<PathBuf>,
+  ram_dirty: bool,
   cartloaded: bool,
-  title: String,
-  rom_size: u8,
-  ram_size: u8,
-  dest_code: u8,
-  lic_code: u8,
-  version: u8,
-  checksum: u16,
+  header: Option<CartHeader>,
 }
 
 impl Cart{
   pub fn new() -> Cart{
     Cart {
       rom: Vec::new(),
+      ram: Vec::new(),
+      mbc: Mbc::RomOnly,
+      events: Vec::new(),
+      save_path: None,
+      ram_dirty: false,
       cartloaded: false,
-      title: String::new(),
-      rom_size: 0,
-      ram_size: 0,
-      dest_code: 0,
-      lic_code: 0,
-      version: 0,
-      checksum: 0,
-    }
-  }
-  pub fn load_rom(&mut self, name: String){
-    self.read_rom(name);
-    self.load_header();
+      header: None,
+    }
+  }
+  /// Load and validate a ROM image. The Cart is left untouched if anything is wrong with it.
+  /// `path` can be a raw .gb/.gbc image or a .zip/.gz archive holding one.
+  /// A .ips/.bps/.ups file with the same name as the ROM is applied automatically, its path is returned.
+  pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> Result<Option<PathBuf>, CartError>{
+    let path: &Path = path.as_ref();
+    let patch: Option<PathBuf> = ["ips", "bps", "ups"].iter()
+      .map(|ext| path.with_extension(ext))
+      .find(|patch| patch.is_file());
+    self.load_rom_patched(path, patch.as_deref())?;
+    return Ok(patch);
+  }
+
+  /// Same as `load_rom`, but with an explicit patch (or none at all) instead of looking for one
+  pub fn load_rom_patched<P: AsRef<Path>>(&mut self, path: P, patch: Option<&Path>) -> Result<(), CartError>{
+    let path: &Path = path.as_ref();
+    let mut rom: Vec<u8> = read_rom(path)?;
+    if let Some(patch_path) = patch {
+      rom = patch::apply(&rom, &read_rom(patch_path)?)?;
+    }
+    if rom.len() < HEADER_END {
+      return Err(CartError::FileTooSmall(rom.len()));
+    }
+    let header: CartHeader = parse_header(&rom);
+    let rom_size: usize = match header.rom_size {
+      Some(size) => size,
+      None => return Err(CartError::BadHeader(format!("unknown ROM size code {:#04X}", header.rom_size_code))),
+    };
+    let ram_size: usize = match header.ram_size {
+      Some(size) => size,
+      None => return Err(CartError::BadHeader(format!("unknown RAM size code {:#04X}", header.ram_size_code))),
+    };
+    if rom.len() != rom_size {
+      return Err(CartError::SizeMismatch { expected: rom_size, actual: rom.len() });
+    }
+    let mbc: Mbc = Mbc::from_header(&header)?;
+
+    // Flush the save of a previously loaded cart before replacing it
+    self.save()?;
+    self.rom = rom;
+    self.mbc = mbc;
+    // MBC2 RAM is built into the controller, the header reports none
+    let ram_len: usize = match self.mbc {
+      Mbc::Mbc2(_) => MBC2_RAM_SIZE,
+      _ => ram_size,
+    };
+    self.ram = vec![0; ram_len];
+    self.ram_dirty = false;
+    self.save_path = None;
+    let battery: bool = header.cart_type.battery;
+    self.header = Some(header);
+    if battery {
+      self.save_path = Some(path.with_extension("sav"));
+      self.load_save();
+    }
     self.cartloaded = true;
+    return Ok(());
   }
   pub fn read(&mut self, addr: u16)->u8{
     if !self.cartloaded {
       panic!("Read from unloaded Cart");
     }
-    return self.rom[addr as usize];
+    match &mut self.mbc {
+      Mbc::RomOnly => match addr {
+        0x0000..=0x7FFF => self.rom[addr as usize],
+        _ => if self.ram.is_empty() {0xFF} else {self.ram[(addr as usize - 0xA000) % self.ram.len()]},
+      },
+      Mbc::Mbc1(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Mbc3(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Mbc5(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Mbc2(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Mmm01(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Huc1(mbc) => mbc.read(&self.rom, &self.ram, addr),
+      Mbc::Huc3(mbc) => mbc.read(&self.rom, &self.ram, addr),
+    }
   }
   pub fn write(&mut self, addr: u16, data: u8){
     if !self.cartloaded {
       panic!("Write to unloaded Cart");
     }
-    self.rom[addr as usize] = data;
+    if (0xA000..=0xBFFF).contains(&addr) {
+      self.ram_dirty = true;
+    }
+    match &mut self.mbc {
+      Mbc::RomOnly => {
+        // No banking hardware, writes to the ROM area are ignored
+        if addr >= 0xA000 && !self.ram.is_empty() {
+          let len: usize = self.ram.len();
+          self.ram[(addr as usize - 0xA000) % len] = data;
+        }
+      },
+      Mbc::Mbc1(mbc) => mbc.write(&mut self.ram, addr, data),
+      Mbc::Mbc3(mbc) => mbc.write(&mut self.ram, addr, data),
+      Mbc::Mbc5(mbc) => {
+        let was_rumbling: bool = mbc.rumble;
+        mbc.write(&mut self.ram, addr, data);
+        if mbc.rumble != was_rumbling {
+          self.events.push(Event::Rumble(mbc.rumble));
+        }
+      },
+      Mbc::Mbc2(mbc) => mbc.write(&mut self.ram, addr, data),
+      Mbc::Mmm01(mbc) => mbc.write(&mut self.ram, addr, data),
+      Mbc::Huc1(mbc) => mbc.write(&mut self.ram, addr, data),
+      Mbc::Huc3(mbc) => mbc.write(&mut self.ram, addr, data),
+    }
   }
 
-  fn read_rom(&mut self, path: String){
-    let mut file: File = File::open(path).expect("Can't open file!");
-    file.read_to_end(&mut self.rom).expect("Couldn't read file");
+  /// Write battery-backed RAM (and the clock for MBC3 and HuC3) to the .sav file next to the ROM.
+  /// Does nothing for carts without a battery or when nothing changed since the last save.
+  pub fn save(&mut self) -> std::io::Result<()> {
+    let path: &PathBuf = match &self.save_path {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    let clock: Option<Vec<u8>> = match &mut self.mbc {
+      Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) => Some(rtc.to_save()),
+      Mbc::Huc3(mbc) => Some(mbc.to_save()),
+      _ => None,
+    };
+    // The clock keeps moving even if RAM didn't, so carts with a clock are always written
+    if !self.ram_dirty && clock.is_none() {
+      return Ok(());
+    }
+
+    let mut data: Vec<u8> = self.ram.clone();
+    if let Some(clock) = clock {
+      data.extend(clock);
+    }
+    let mut file: File = File::create(path)?;
+    file.write_all(&data)?;
+    self.ram_dirty = false;
+    return Ok(());
   }
-  fn load_header(&mut self){
 
-    //header data
-    let header: Vec<u8> = self.rom[0x0104..0x0150].to_vec();
-    // Title
-    let title_str: &str = std::str::from_utf8(&self.rom[0x0134..0x0143]).unwrap();
-    let mut title: String = String::new();
-    title_str.clone_into(&mut title);
-    self.title = title.clone();
+  fn load_save(&mut self) {
+    let path: &PathBuf = match &self.save_path {
+      Some(path) => path,
+      None => return,
+    };
+    let mut data: Vec<u8> = Vec::new();
+    match File::open(path) {
+      Ok(mut file) => {
+        if file.read_to_end(&mut data).is_err() {
+          return;
+        }
+      },
+      // No save yet
+      Err(_) => return,
+    }
 
-    //Ram and Rom Size
-    self.ram_size = self.rom[0x0148];
-    self.rom_size = self.rom[0x0149];
-    self.dest_code = self.rom[0x014A];
-    self.lic_code = self.rom[0x014B];
-    self.version = self.rom[0x014C];
+    let ram_len: usize = self.ram.len().min(data.len());
+    self.ram[..ram_len].copy_from_slice(&data[..ram_len]);
+    match &mut self.mbc {
+      Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) => rtc.load_save(&data[ram_len..]),
+      Mbc::Huc3(mbc) => mbc.load_save(&data[ram_len..]),
+      _ => {},
+    }
+  }
 
-    //Checksum
-    let mut checksum: u8 = 0;
-    for byte in 0x0134..0x014D{
-        let val: u8 = self.rom[byte];
-        checksum = checksum.wrapping_sub(val).wrapping_sub(1);
+  /// Decoded header of the loaded ROM
+  pub fn header(&self) -> Option<&CartHeader> {
+    return self.header.as_ref();
+  }
+
+  /// Drain the events raised by the cartridge hardware since the last call
+  pub fn take_events(&mut self) -> Vec<Event> {
+    return std::mem::take(&mut self.events);
+  }
+}
+
+impl Drop for Cart {
+  fn drop(&mut self) {
+    if let Err(err) = self.save() {
+      eprintln!("Failed to write save file: {}", err);
     }
-    self.checksum = self.checksum;
+  }
+}
 
-    println!("Cartridge Loaded");
-    println!("\tTitle: {}", self.title);
-    println!("\tRom Size: {}", self.rom_size);
-    println!("\tRam Size: {}", self.ram_size);
-    println!("\tVersion: {}", self.version);
-    println!("Checksum: {}", if checksum == self.rom[0x014D] {"Passed"} else {"Failed"})
-    //for (uint16_t i=0x0134; i<=0x014C; i++) { x = x - rom_data[i] - 1; } 
+/// Header of a ROM image that reaches at least to 0x0150. MMM01 dumps start with the first
+/// game, the menu that boots first keeps its header in the last 32 KiB
+fn parse_header(rom: &[u8]) -> CartHeader {
+  let header: CartHeader = CartHeader::parse(rom);
+  if header.cart_type.mapper != Mapper::Mmm01 && rom.len() > 2 * ROM_BANK_SIZE {
+    let menu: CartHeader = CartHeader::parse(&rom[rom.len() - 2 * ROM_BANK_SIZE..]);
+    if menu.cart_type.mapper == Mapper::Mmm01 {
+      return menu;
+    }
+  }
+  return header;
+}
 
+/// Read a ROM image, unpacking it first if the file is a zip or gzip archive
+fn read_rom(path: &Path) -> Result<Vec<u8>, CartError> {
+  let mut file: File = File::open(path)?;
+  let mut data: Vec<u8> = Vec::new();
+  file.read_to_end(&mut data)?;
+
+  // Archives are recognised by their magic number rather than the file name
+  if data.starts_with(b"PK\x03\x04") {
+    return read_zip(data);
+  }
+  if data.starts_with(&[0x1F, 0x8B]) {
+    let mut rom: Vec<u8> = Vec::new();
+    GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+    return Ok(rom);
   }
-}
\ No newline at end of file
+  return Ok(data);
+}
+
+/// Extract the first .gb or .gbc entry of a zip archive
+fn read_zip(data: Vec<u8>) -> Result<Vec<u8>, CartError> {
+  let mut archive: ZipArchive<std::io::Cursor<Vec<u8>>> = match ZipArchive::new(std::io::Cursor::new(data)) {
+    Ok(archive) => archive,
+    Err(err) => return Err(CartError::Archive(err.to_string())),
+  };
+  for i in 0..archive.len() {
+    let mut entry = match archive.by_index(i) {
+      Ok(entry) => entry,
+      Err(err) => return Err(CartError::Archive(err.to_string())),
+    };
+    let name: String = entry.name().to_ascii_lowercase();
+    if entry.is_file() && (name.ends_with(".gb") || name.ends_with(".gbc")) {
+      let mut rom: Vec<u8> = Vec::new();
+      entry.read_to_end(&mut rom)?;
+      return Ok(rom);
+    }
+  }
+  return Err(CartError::Archive(String::from("no .gb or .gbc file in the archive")));
+}
+
+fn unix_time() -> u64 {
+  match SystemTime::now().duration_since(UNIX_EPOCH) {
+    Ok(time) => time.as_secs(),
+    Err(_) => 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// ROM where the first two bytes of each bank hold the bank number, low byte first
+  fn banked_rom(banks: usize) -> Vec<u8> {
+    let mut rom: Vec<u8> = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+      rom[bank * ROM_BANK_SIZE] = bank as u8;
+      rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    return rom;
+  }
+
+  /// Bank number mapped at `addr`, which has to be the start of a ROM area
+  fn bank_at(read: &mut dyn FnMut(u16) -> u8, addr: u16) -> usize {
+    return read(addr) as usize | (read(addr + 1) as usize) << 8;
+  }
+
+  #[test]
+  fn mbc1_bank_0_maps_to_1() {
+    let rom: Vec<u8> = banked_rom(64);
+    let mut ram: Vec<u8> = Vec::new();
+    let mut mbc: Mbc1 = Mbc1::new();
+    mbc.write(&mut ram, 0x2000, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 1);
+    mbc.write(&mut ram, 0x2000, 0x05);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 5);
+    // Only the lower 5 bits are checked, so 0x20 turns into 0x21
+    mbc.write(&mut ram, 0x4000, 0x01);
+    mbc.write(&mut ram, 0x2000, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x21);
+  }
+
+  #[test]
+  fn mbc1_mode_1_upper_bits() {
+    let rom: Vec<u8> = banked_rom(128);
+    let mut ram: Vec<u8> = vec![0; 4 * RAM_BANK_SIZE];
+    let mut mbc: Mbc1 = Mbc1::new();
+    mbc.write(&mut ram, 0x0000, 0x0A);
+    mbc.write(&mut ram, 0x4000, 0x02);
+    mbc.write(&mut ram, 0x2000, 0x03);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x43);
+    // Mode 0 keeps bank 0 and RAM bank 0 in place
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x0000), 0x00);
+    mbc.write(&mut ram, 0xA000, 0x11);
+    assert_eq!(ram[0], 0x11);
+    // Mode 1 applies the upper bits to 0x0000-0x3FFF and RAM as well
+    mbc.write(&mut ram, 0x6000, 0x01);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x0000), 0x40);
+    mbc.write(&mut ram, 0xA000, 0x22);
+    assert_eq!(ram[2 * RAM_BANK_SIZE], 0x22);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 0x22);
+  }
+
+  #[test]
+  fn mbc1_ram_disabled_reads_ff() {
+    let rom: Vec<u8> = banked_rom(4);
+    let mut ram: Vec<u8> = vec![0; RAM_BANK_SIZE];
+    let mut mbc: Mbc1 = Mbc1::new();
+    mbc.write(&mut ram, 0xA000, 0x12);
+    assert_eq!(ram[0], 0x00);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 0xFF);
+  }
+
+  #[test]
+  fn mbc5_9_bit_banks() {
+    let rom: Vec<u8> = banked_rom(512);
+    let mut ram: Vec<u8> = Vec::new();
+    let mut mbc: Mbc5 = Mbc5::new(false);
+    mbc.write(&mut ram, 0x2000, 0x05);
+    mbc.write(&mut ram, 0x3000, 0x01);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x105);
+    mbc.write(&mut ram, 0x2000, 0xFF);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x1FF);
+    // Bank 0 can be mapped into 0x4000-0x7FFF
+    mbc.write(&mut ram, 0x2000, 0x00);
+    mbc.write(&mut ram, 0x3000, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x000);
+  }
+
+  #[test]
+  fn mbc5_rumble_bit() {
+    let mut ram: Vec<u8> = vec![0; 16 * RAM_BANK_SIZE];
+    let mut mbc: Mbc5 = Mbc5::new(true);
+    mbc.write(&mut ram, 0x4000, 0x0B);
+    assert!(mbc.rumble);
+    assert_eq!(mbc.ram_bank, 0x03);
+  }
+
+  #[test]
+  fn mbc2_nibble_ram() {
+    let rom: Vec<u8> = banked_rom(16);
+    let mut ram: Vec<u8> = vec![0; MBC2_RAM_SIZE];
+    let mut mbc: Mbc2 = Mbc2::new();
+    // Address bit 8 set selects the ROM bank register instead of RAM enable
+    mbc.write(&mut ram, 0x0100, 0x0A);
+    mbc.write(&mut ram, 0xA000, 0xAB);
+    assert_eq!(ram[0], 0x00);
+    mbc.write(&mut ram, 0x0000, 0x0A);
+    mbc.write(&mut ram, 0xA000, 0xAB);
+    assert_eq!(ram[0], 0x0B);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 0xFB);
+    // 512 nibbles echoed through 0xBFFF
+    assert_eq!(mbc.read(&rom, &ram, 0xA200), 0xFB);
+    assert_eq!(mbc.read(&rom, &ram, 0xBE00), 0xFB);
+  }
+
+  #[test]
+  fn mbc2_bank_0_maps_to_1() {
+    let rom: Vec<u8> = banked_rom(16);
+    let mut ram: Vec<u8> = vec![0; MBC2_RAM_SIZE];
+    let mut mbc: Mbc2 = Mbc2::new();
+    mbc.write(&mut ram, 0x0100, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 1);
+    mbc.write(&mut ram, 0x0100, 0x0F);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 15);
+  }
+
+  #[test]
+  fn mbc3_bank_0_maps_to_1() {
+    let rom: Vec<u8> = banked_rom(128);
+    let mut ram: Vec<u8> = Vec::new();
+    let mut mbc: Mbc3 = Mbc3::new(false);
+    mbc.write(&mut ram, 0x2000, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 1);
+    mbc.write(&mut ram, 0x2000, 0x7F);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x7F);
+  }
+
+  #[test]
+  fn mbc3_rtc_latch() {
+    let rom: Vec<u8> = banked_rom(4);
+    let mut ram: Vec<u8> = Vec::new();
+    let mut mbc: Mbc3 = Mbc3::new(true);
+    mbc.write(&mut ram, 0x0000, 0x0A);
+    // Halt the clock so wall-clock time doesn't move it
+    mbc.write(&mut ram, 0x4000, 0x0C);
+    mbc.write(&mut ram, 0xA000, 0x40);
+    mbc.write(&mut ram, 0x4000, 0x08);
+    mbc.write(&mut ram, 0xA000, 30);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 30);
+
+    // The live register moves on, reads stay on the latched copy
+    mbc.rtc.as_mut().unwrap().seconds = 45;
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 30);
+    // Only a 0x00 then 0x01 write latches
+    mbc.write(&mut ram, 0x6000, 0x01);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 30);
+    mbc.write(&mut ram, 0x6000, 0x00);
+    mbc.write(&mut ram, 0x6000, 0x01);
+    assert_eq!(mbc.read(&rom, &ram, 0xA000), 45);
+  }
+
+  #[test]
+  fn mbc3_rtc_rolls_over() {
+    let mut rtc: Rtc = Rtc::new();
+    rtc.days = 0x1FF;
+    rtc.hours = 23;
+    rtc.minutes = 59;
+    rtc.seconds = 59;
+    rtc.last_time = unix_time() - 1;
+    rtc.update();
+    assert_eq!((rtc.days, rtc.hours, rtc.minutes), (0, 0, 0));
+    // A second may tick over while the test runs
+    assert!(rtc.seconds <= 1);
+    assert!(rtc.carry);
+  }
+
+  #[test]
+  fn mmm01_menu_mask_survives_mapping() {
+    let rom: Vec<u8> = banked_rom(64);
+    let mut ram: Vec<u8> = Vec::new();
+    let mut mbc: Mmm01 = Mmm01::new();
+    // Menu: mask ROM bank bits 1-2, pick bank 0x0E of the outer slice 0x20 and map
+    mbc.write(&mut ram, 0x6000, 0b11 << 2);
+    mbc.write(&mut ram, 0x2000, 0x2E);
+    mbc.write(&mut ram, 0x0000, 1 << 6);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x2E);
+    // The game only controls bits 0, 3 and 4 from here on
+    mbc.write(&mut ram, 0x2000, 0x19);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x3F);
+    // With the unmasked bits all 0, bank 0 is still turned into 1
+    mbc.write(&mut ram, 0x2000, 0x00);
+    assert_eq!(bank_at(&mut |addr| mbc.read(&rom, &ram, addr), 0x4000), 0x27);
+  }
+
+  #[test]
+  fn mmm01_header_found_at_the_end() {
+    let mut rom: Vec<u8> = vec![0; 4 * ROM_BANK_SIZE];
+    // The first game claims to be an MBC1 cart, the menu header says MMM01
+    rom[0x0147] = 0x01;
+    rom[2 * ROM_BANK_SIZE + 0x0147] = 0x0B;
+    rom[2 * ROM_BANK_SIZE + 0x0148] = 0x01;
+    assert_eq!(parse_header(&rom).cart_type.mapper, Mapper::Mmm01);
+    assert_eq!(parse_header(&rom).rom_size, Some(rom.len()));
+    rom[2 * ROM_BANK_SIZE + 0x0147] = 0x01;
+    assert_eq!(parse_header(&rom).cart_type.mapper, Mapper::Mbc1);
+  }
+
+  #[test]
+  fn huc3_clock_survives_a_save() {
+    let mut huc3: Huc3 = Huc3::new();
+    huc3.minutes = 59;
+    huc3.days = 0x123;
+    huc3.rtc_memory[0x10] = 0x0A;
+    let mut data: Vec<u8> = huc3.to_save();
+    assert_eq!(data.len(), HUC3_SAVE_SIZE);
+    // Saved two minutes ago
+    let saved: u64 = unix_time() - 120;
+    data[HUC3_SAVE_SIZE - 8..].copy_from_slice(&saved.to_le_bytes());
+
+    let mut loaded: Huc3 = Huc3::new();
+    loaded.load_save(&data);
+    assert_eq!(loaded.minutes, 61);
+    assert_eq!(loaded.days, 0x123);
+    assert_eq!(loaded.rtc_memory[0x10], 0x0A);
+  }
+
+  #[test]
+  fn huc3_ignores_a_short_footer() {
+    let mut huc3: Huc3 = Huc3::new();
+    huc3.load_save(&[0xFF; 12]);
+    assert_eq!(huc3.minutes, 0);
+    assert_eq!(huc3.days, 0);
+  }
+}
@@ -7,8 +7,12 @@
 /// https://forums.nesdev.org/viewtopic.php?t=15944 - DAA instruction
 /// 
 use crate::bus::Bus;
+use crate::event::Event;
+use crate::interrupt::Interrupt;
+use crate::joypad::Button;
 use crate::log::Logger;
 use crate::log::create_file;
+use std::path::Path;
 
 #[derive(Clone, Copy)]
 enum Reg8{ A, B, C, D, E, F, H, L}
@@ -78,7 +82,8 @@ impl Registers{
     }
     fn set_af(&mut self, val: u16){
         self.a = (val >> 8) as u8;
-        self.f = ((val << 8) >> 8) as u8;   
+        // The lower nibble of F doesn't exist and always reads 0
+        self.f = (val & 0xF0) as u8;
     }
     fn set_bc(&mut self, val: u16){
         self.b = (val >> 8) as u8;
@@ -145,15 +150,23 @@ impl Registers{
  * curr_instr: opcode of the current instruction being executed
  * IME: the IME flag which is used to disable all interrupts, overriding any enabled bits in the IE register.
  * halted: pauses emulatiom
+ * ime_delay: EI was just executed, IME is set after the next instruction
+ * halt_bug: HALT was skipped with an interrupt pending and IME off, the next opcode fetch doesn't increment PC
+ * stopped: STOP low-power mode, the system clock is stopped until a button is pressed
+ * locked: an illegal opcode hung the CPU
  */
 pub struct CPU{
     reg: Registers,
     ime: bool,
+    ime_delay: bool,
     halted: bool,
+    halt_bug: bool,
+    stopped: bool,
+    locked: bool,
     bus: Bus,
     cycles: u64,
     log: Logger,
-    
+    events: Vec<Event>,
 }
 impl CPU {
     pub fn new(bus_in: Bus) -> Self{
@@ -161,15 +174,32 @@ impl CPU {
         Self{
             reg: Registers::new(),
             ime: false,
+            ime_delay: false,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
             bus: bus_in,
             cycles: 0,
             log: Logger {log: file},
+            events: Vec::new(),
         } 
     }
 
     pub fn step(&mut self){
-        let opcode: u8 = self.fetch();
+        if self.ime_delay {
+            // Takes effect before the instruction after EI, so a DI there still wins
+            self.ime_delay = false;
+            self.ime = true;
+        }
+        let opcode: u8 = if self.halt_bug {
+            // PC fails to increment, the byte after HALT is executed twice
+            self.halt_bug = false;
+            self.read(self.reg.pc)
+        }
+        else {
+            self.fetch()
+        };
 
         self.log.write_instr(opcode);
         self.log_reg();
@@ -177,6 +207,65 @@ impl CPU {
         self.execute(opcode);
     }
 
+    /// Flush battery-backed cartridge RAM to its .sav file
+    pub fn save_cart(&mut self) -> std::io::Result<()> {
+        return self.bus.save_cart();
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        return self.bus.framebuffer();
+    }
+
+    /// Audio produced since the last call, interleaved left and right at the sample rate given to IO
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        return self.bus.take_samples();
+    }
+
+    /// Record audio to a stereo WAV file, and with `separate` also each channel to
+    /// path.ch1.wav ... path.ch4.wav. Works without an audio device
+    pub fn start_recording(&mut self, path: &Path, separate: bool) -> std::io::Result<()> {
+        return self.bus.start_recording(path, separate);
+    }
+
+    /// Finish the WAV files, they are also finished when the CPU is dropped
+    pub fn stop_recording(&mut self) -> std::io::Result<()> {
+        return self.bus.stop_recording();
+    }
+
+    /// Leave APU channel 0 - 3 out of the mix, recordings of the separate channels still get it.
+    /// Other channel numbers are ignored
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.bus.set_channel_muted(channel, muted);
+    }
+
+    /// While any channel is soloed only the soloed channels are mixed
+    pub fn set_channel_solo(&mut self, channel: usize, soloed: bool) {
+        self.bus.set_channel_solo(channel, soloed);
+    }
+
+    /// Recent DAC output of APU channel 0 - 3 at the host sample rate, oldest first, for oscilloscopes.
+    /// Empty for other channel numbers
+    pub fn channel_scope(&self, channel: usize) -> Vec<f32> {
+        return self.bus.channel_scope(channel);
+    }
+
+    /// Press or release a button on the joypad
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.bus.set_button(button, pressed);
+    }
+
+    /// In STOP mode nothing runs, so no frames are produced until a button is pressed
+    pub fn stopped(&self) -> bool {
+        return self.stopped;
+    }
+
+    /// Events raised by the hardware since the last call, for the frontend to handle
+    pub fn take_events(&mut self) -> Vec<Event> {
+        let mut events: Vec<Event> = std::mem::take(&mut self.events);
+        events.append(&mut self.bus.take_events());
+        return events;
+    }
+
     pub fn log_reg(&mut self){
         self.log.write(
         format!("A: {:#01x} F: {:#01x} B: {:#01x} C: {:#01x} D: {:#01x} E: {:#01x} H: {:#01x} L: {:#01x} PC:{:01x} SP:{:01x}\n",
@@ -184,22 +273,34 @@ impl CPU {
     }
 
     ///
+    /// Reads the byte at PC and advances it, used for opcodes and immediate operands
     /// Returns
-    ///     opcode: u8 - opcode of instructionS
+    ///     opcode: u8 - opcode of instruction or operand byte
     /// 
     fn fetch(&mut self)->u8{
-        let op: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let op: u8 = self.read(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
         return op;
     }
 
+    /// Memory read, takes one machine cycle
+    fn read(&mut self, addr: u16) -> u8{
+        self.clock_tick();
+        return self.bus.read(addr);
+    }
+
+    /// Memory write, takes one machine cycle
+    fn write(&mut self, addr: u16, data: u8){
+        self.clock_tick();
+        self.bus.write(addr, data);
+    }
+
     /*
      * Executes current instruction
      */
     fn execute(&mut self, opcode: u8){
         match opcode {
-            0x00 => self.clock_tick(),
+            0x00 => {},
             0x01 => self.LD_r16_n16(Reg16::BC),
             0x02 => self.LD_MR_R(Reg16::BC, Reg8::A),
             0x03 => self.INC_r16(Reg16::BC),
@@ -342,7 +443,7 @@ impl CPU {
             0x8C => self.ADC_A_r8(Reg8::H),
             0x8D => self.ADC_A_r8(Reg8::L),
             0x8E => self.ADC_A_HL(),
-            0x8F => self.LD_R_R(Reg8::A, Reg8::A),
+            0x8F => self.ADC_A_r8(Reg8::A),
             0x90 => self.SUB(Reg8::B),
             0x91 => self.SUB(Reg8::C),
             0x92 => self.SUB(Reg8::D),
@@ -391,7 +492,7 @@ impl CPU {
             0xBD => self.CP(Reg8::L),
             0xBE => self.CP_HL(),
             0xBF => self.CP(Reg8::A),
-            0xC0 => self.RET(Cond::NC),
+            0xC0 => self.RET(Cond::NZ),
             0xC1 => self.POP(Reg16::BC),
             0xC2 => self.JP_a16(Cond::NZ),
             0xC3 => self.JP_a16(Cond::NONE),
@@ -405,7 +506,7 @@ impl CPU {
             0xCB => self.CB(),
             0xCC => self.CALL(Cond::Z),
             0xCD => self.CALL(Cond::NONE),
-            0xCE => self.ADC_A_r8(Reg8::A),
+            0xCE => self.ADC_A_n8(),
             0xCF => self.RST(0x08),
             0xD0 => self.RET(Cond::NC),
             0xD1 => self.POP(Reg16::DE),
@@ -419,7 +520,7 @@ impl CPU {
             0xDA => self.JP_a16(Cond::C),
             0xDC => self.CALL(Cond::C),
             0xDE => self.SBC_D8(),
-            0xDF => self.RST(18),
+            0xDF => self.RST(0x18),
             0xE0 => self.LDH_A8_A(),
             0xE1 => self.POP(Reg16::HL),
             0xE2 => self.LD_C_A(),
@@ -444,19 +545,31 @@ impl CPU {
             0xFB => self.EI(),
             0xFE => self.CP_d8(),
             0xFF => self.RST(0x38),
-            _ => panic!("Invalid opcode used")
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => self.ILLEGAL(opcode),
         }
 
     }
 
     pub fn run(&mut self){
-        
+        if self.locked {
+            // Nothing but a reset gets the CPU out of a lock-up, the rest of the hardware keeps going
+            self.clock_tick();
+            return;
+        }
+        if self.stopped {
+            // Nothing on the bus runs while stopped, only a joypad line going low restarts the clock
+            if self.bus.stop_wake() {
+                self.stopped = false;
+            }
+            return;
+        }
         if !self.halted {
             self.step();
         }
         else{
             self.clock_tick();
-            if self.reg.ie != 0 {
+            // Any requested and enabled interrupt wakes the CPU, even with IME off
+            if self.bus.pending_interrupt().is_some() {
                 self.halted = false;
             }
         }
@@ -466,41 +579,42 @@ impl CPU {
         }
     }
     
+    /// Interrupt dispatch, 5 machine cycles:
+    /// 2 idle cycles, push PC high, push PC low, then jump to the vector
     fn handle_interrupt(&mut self){
+        if self.bus.pending_interrupt().is_none() {
+            return;
+        }
+        self.ime = false;
+        self.halted = false;
+        self.clock_tick();
+        self.clock_tick();
+
         let pc_hi: u8 = ((self.reg.pc & 0xFF00) >> 8) as u8;
         let pc_lo: u8 = (self.reg.pc & 0x00FF) as u8;
         self.stkpush(pc_hi);
+        // The vector is picked after the high byte push, which can overwrite IE at 0xFFFF.
+        // If that leaves nothing pending the dispatch is cancelled and jumps to 0x0000.
+        let it: Option<Interrupt> = self.bus.pending_interrupt();
         self.stkpush(pc_lo);
 
-        
-        let it_vblank: u8 = 1;
-        let it_lcd_stat: u8 = 2;
-        let it_timer: u8 = 4;
-        let it_serial: u8 = 8;
-        let it_joypad: u8 = 16;
-
-        if((self.reg.ie & it_vblank) != 0 ) && ((self.reg.ifr & it_vblank) != 0){
-            self.reg.pc = 0x40;
-        }
-        else if( (self.reg.ie & it_lcd_stat) != 0) && ((self.reg.ifr & it_lcd_stat) != 0) {
-            self.reg.pc = 0x48;
-        }
-        else if( (self.reg.ie & it_timer) != 0) && ((self.reg.ifr & it_timer) != 0) {
-            self.reg.pc = 0x50;
-        }
-        else if( (self.reg.ie & it_serial) != 0) && ((self.reg.ifr & it_serial) != 0) {
-            self.reg.pc = 0x58;
-        }
-        else if( (self.reg.ie & it_joypad) != 0) && ((self.reg.ifr & it_joypad) != 0) {
-            self.reg.pc = 0x60;
+        match it {
+            Some(it) => {
+                self.bus.acknowledge_interrupt(it);
+                self.reg.pc = it.vector();
+            },
+            None => self.reg.pc = 0x0000,
         }
-
-        self.reg.ifr &= !self.reg.ifr;
-        self.halted = false;
-        self.ime = false;
-        
+        self.clock_tick();
+    }
+    /// One machine cycle (4 clocks) passes for the rest of the system.
+    /// Memory accesses go through read/write which tick before touching the bus,
+    /// so timer, PPU and DMA state is exactly what the CPU sees at that point of the instruction.
+    /// Instructions only call this directly for internal cycles with no bus access.
+    fn clock_tick(&mut self){
+        self.cycles += 1;
+        self.bus.tick();
     }
-    fn clock_tick(&mut self){ self.cycles += 1; }
 
     /* Instructions */ 
     
@@ -508,284 +622,179 @@ impl CPU {
 
     /// Load immediate little-endian 16-bit data to 16 bit reg 
     fn LD_r16_n16(&mut self, r: Reg16){
-        let lsb: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let lsb: u8 = self.fetch();
         
-        let msb: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let msb: u8 = self.fetch();
 
         let n16: u16 = (msb as u16) << 8 | lsb as u16;
         self.reg.set_reg16(r, n16);
-        self.clock_tick();
     }
     fn LD_r8_n8(&mut self, r: Reg8){
-        let data: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let data: u8 = self.fetch();
         self.reg.set_reg8(r, data);
-        self.clock_tick();
     }
     /// Load value at reg8 r into memory address stored in reg16 mr
     fn LD_MR_R(&mut self, mr: Reg16, r: Reg8){
-        self.bus.write(self.reg.get_reg16(mr), self.reg.get_reg8(r));
-        self.clock_tick();
-        self.clock_tick();
+        self.write(self.reg.get_reg16(mr), self.reg.get_reg8(r));
     }
 
     fn LD_R_MR(&mut self, r: Reg8, mr: Reg16){
-        let val: u8 = self.bus.read(self.reg.get_reg16(mr));
-        self.clock_tick();
+        let val: u8 = self.read(self.reg.get_reg16(mr));
         self.reg.set_reg8(r, val);
-        self.clock_tick();
     }
 
     fn LD_R_R(&mut self, r1: Reg8, r2: Reg8){
         self.reg.set_reg8(r1, self.reg.get_reg8(r2));
-        self.clock_tick();
     }
     fn LD_SP_HL(&mut self){
         self.reg.sp = self.reg.get_hl();
         self.clock_tick();
-        self.clock_tick();
     }
     ///reads memory from hl, increments hl, stores it in r
     fn LD_R_HLI(&mut self, r: Reg8){
         let hl: u16 = self.reg.get_hl();
-        let val: u8 = self.bus.read(hl);
-        self.clock_tick();
+        let val: u8 = self.read(hl);
         self.reg.set_hl(hl.wrapping_add(1));
         self.reg.set_reg8(r, val);
-        self.clock_tick();
     }
 
     fn LD_HLI_R(&mut self, r: Reg8){
         let data: u8 = self.reg.get_reg8(r);
-        self.bus.write(self.reg.get_hl(), data);
-        self.clock_tick();
+        self.write(self.reg.get_hl(), data);
         self.reg.set_hl(self.reg.get_hl().wrapping_add(1));
-        self.clock_tick();
     }
     fn LD_HLD_R(&mut self, r: Reg8){
         let data: u8 = self.reg.get_reg8(r);
-        self.bus.write(self.reg.get_hl(), data);
-        self.clock_tick();
+        self.write(self.reg.get_hl(), data);
         self.reg.set_hl(self.reg.get_hl().wrapping_sub(1));
-        self.clock_tick();
     }
     fn LD_R_HLD(&mut self, r: Reg8){
         let hl: u16 = self.reg.get_hl();
-        let val: u8 = self.bus.read(hl);
-        self.clock_tick();
+        let val: u8 = self.read(hl);
         self.reg.set_hl(hl.wrapping_sub(1));
         self.reg.set_reg8(r, val);
-        self.clock_tick();  
     }
 
     fn LD_A16_A(&mut self){
-        let lo: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        let hi: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
+        let lo: u8 = self.fetch();
+        let hi: u8 = self.fetch();
         let addr: u16 = ((hi as u16) << 8) | lo as u16 ;
-        self.bus.write(addr, self.reg.a);
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
+        self.write(addr, self.reg.a);
     }
 
     fn LD_A_A16(&mut self){
-        let lo: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        let hi: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
+        let lo: u8 = self.fetch();
+        let hi: u8 = self.fetch();
         let addr: u16 = ((hi as u16) << 8) | lo as u16 ;
-        let data: u8 = self.bus.read(addr);
+        let data: u8 = self.read(addr);
         self.reg.a = data;
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
     }
     ///Load value of stack pointer into memory address stored at addres of value a16
     /// a16 :=
     fn LD_A16_SP(&mut self){
-        let lsb: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
-        let msb: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let lsb: u8 = self.fetch();
+        let msb: u8 = self.fetch();
         let a16: u16 = (msb as u16)  << 8 | lsb as u16;
-        self.clock_tick();
         //store lower byte of sp first, then upper byte
         let lsb_sp: u8 = (self.reg.sp & 0xff) as u8;
         let msb_sp: u8 = (self.reg.sp >> 8) as u8;
-        self.bus.write(a16, lsb_sp);
-        self.clock_tick();
-        self.bus.write(a16, msb_sp);
-        self.clock_tick();
+        self.write(a16, lsb_sp);
+        self.write(a16.wrapping_add(1), msb_sp);
     }
 
     fn LD_MR_n8(&mut self, mr: Reg16){
-        let data: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
-        self.bus.write(self.reg.get_reg16(mr), data);
-        self.clock_tick();
-        self.clock_tick();
+        let data: u8 = self.fetch();
+        self.write(self.reg.get_reg16(mr), data);
     }
 
     fn LDH_A8_A(&mut self){
         //LDH (a8),A has alternative mnemonic LD ($FF00+a8),A
-         let a8: u8 = self.bus.read(self.reg.pc);
-         self.reg.pc += 1;
+         let a8: u8 = self.fetch();
          let mut addr: u16 = 0xFF00;
          addr = addr.wrapping_add(a8 as u16);
-         self.bus.write(addr, self.reg.a);
-         self.clock_tick();
-         self.clock_tick();
-         self.clock_tick();
+         self.write(addr, self.reg.a);
     }
 
     fn LDH_A_A8(&mut self){
          // LDH A,(a8) has alternative mnemonic LD A,($FF00+a8)
-         let a8: u8 = self.bus.read(self.reg.pc);
-         self.reg.pc += 1;
+         let a8: u8 = self.fetch();
          let mut addr: u16 = 0xFF00;
          addr = addr.wrapping_add(a8 as u16);
-         let data: u8 = self.bus.read(addr);
+         let data: u8 = self.read(addr);
          self.reg.a = data;
-         self.clock_tick();
-         self.clock_tick();
-         self.clock_tick();
     }
     fn LD_A_C(&mut self){
         let addr: u16 = 0xFF00 + (self.reg.c as u16);
-        let data: u8 = self.bus.read(addr);
+        let data: u8 = self.read(addr);
         self.reg.a = data;
-        self.clock_tick();
-        self.clock_tick();
     }
     fn LD_C_A(&mut self){
         let addr: u16 = 0xFF00 + (self.reg.c as u16);
-        self.bus.write(addr, self.reg.a);
-        self.clock_tick();
-        self.clock_tick();
+        self.write(addr, self.reg.a);
     }
     
     fn LD_HL_SP_E8(&mut self){
-        let e8: i8 = self.bus.read(self.reg.pc) as i8;
-        self.reg.pc += 1;
+        let e8: i8 = self.fetch() as i8;
+        let val: u16 = self.sp_add_e8(e8);
+        self.reg.set_hl(val);
         self.clock_tick();
-        self.reg.set_hl(self.reg.sp.wrapping_add_signed(e8 as i16));
-        self.reg.set_z(false);
-        self.reg.set_n(false);
-        if (self.reg.get_hl() & 0xF0) > (self.reg.sp & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if self.reg.get_hl() < self.reg.sp {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
     }
     //Arithmetic
     fn INC_r8(&mut self, r: Reg8){
-        self.reg.set_reg8(r, self.reg.get_reg8(r).wrapping_add(1));
+        let val: u8 = self.reg.get_reg8(r).wrapping_add(1);
+        self.reg.set_reg8(r, val);
         //flags Z 0 H -
-        self.reg.set_z(self.reg.get_reg8(r) == 0);
+        self.reg.set_z(val == 0);
         self.reg.set_n(false);
-        self.reg.set_h(self.reg.get_reg8(r) & 0x0F != 0);
-        self.clock_tick();
+        self.reg.set_h(val & 0x0F == 0);
     }
     fn INC_r16(&mut self, r: Reg16){
         self.reg.set_reg16(r, self.reg.get_reg16(r).wrapping_add(1));
         self.clock_tick();
-        self.clock_tick();
     }
     fn INC_MR(&mut self, mr: Reg16){
-        let mut data: u8 = self.bus.read(self.reg.get_reg16(mr));
-        self.clock_tick();
+        let mut data: u8 = self.read(self.reg.get_reg16(mr));
         data = data.wrapping_add(1);
-        self.clock_tick();
-        self.bus.write(self.reg.get_reg16(mr), data);
-        self.clock_tick();
+        //flags Z 0 H -
+        self.reg.set_z(data == 0);
+        self.reg.set_n(false);
+        self.reg.set_h(data & 0x0F == 0);
+        self.write(self.reg.get_reg16(mr), data);
     }
     fn DEC_r8(&mut self, r: Reg8){
-        self.reg.set_reg8(r, self.reg.get_reg8(r).wrapping_sub(1));
-        //flags Z 0 H -
-        self.reg.set_z(self.reg.get_reg8(r) == 0);
+        let val: u8 = self.reg.get_reg8(r).wrapping_sub(1);
+        self.reg.set_reg8(r, val);
+        //flags Z 1 H -
+        self.reg.set_z(val == 0);
         self.reg.set_n(true);
-        self.reg.set_h(self.reg.get_reg8(r) & 0x0F != 0);
-        self.clock_tick();
+        self.reg.set_h(val & 0x0F == 0x0F);
     }
     fn DEC_r16(&mut self, r: Reg16){
         self.reg.set_reg16(r, self.reg.get_reg16(r).wrapping_sub(1));
         self.clock_tick();
-        self.clock_tick(); 
     }
     fn DEC_MR(&mut self, mr: Reg16){
-        let mut data: u8 = self.bus.read(self.reg.get_reg16(mr));
-        self.clock_tick();
+        let mut data: u8 = self.read(self.reg.get_reg16(mr));
         data = data.wrapping_sub(1);
-        self.clock_tick();
-        self.bus.write(self.reg.get_reg16(mr), data);
-        self.clock_tick();
+        //flags Z 1 H -
+        self.reg.set_z(data == 0);
+        self.reg.set_n(true);
+        self.reg.set_h(data & 0x0F == 0x0F);
+        self.write(self.reg.get_reg16(mr), data);
     }
     fn ADD_r8_r8(&mut self, r1: Reg8, r2: Reg8){
-
         let val1: u8 = self.reg.get_reg8(r1);
         let val2: u8 = self.reg.get_reg8(r2);
-        let sum: u8 = val1.wrapping_add(val2);
+        let sum: u8 = self.alu_add(val1, val2, false);
         self.reg.set_reg8(r1, sum);
-
-        //flags - Z 0 H C
-        self.reg.set_z(sum == 0);
-        self.reg.set_n(false);
-        if (sum & 0xF0) > (val1 & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if sum < val1{
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.clock_tick();
     }
     fn ADD_r8_mr(&mut self, r: Reg8, mr: Reg16){
         //Z 0 H C
-        let mr_val: u8 = self.bus.read( self.reg.get_reg16(mr) );
-        let r_val: u8 = self.reg.get_reg8(r);;
-        let sum: u8 = r_val.wrapping_add(mr_val);
-        self.clock_tick();
+        let mr_val: u8 = self.read( self.reg.get_reg16(mr) );
+        let r_val: u8 = self.reg.get_reg8(r);
 
+        let sum: u8 = self.alu_add(r_val, mr_val, false);
         self.reg.set_reg8(r, sum);
-        self.reg.set_z(sum == 0);
-        self.reg.set_n(false);
-        if (sum & 0xF0) > (r_val & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if sum < r_val {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.clock_tick();
     }
     fn ADD_r16_r16(&mut self, r1: Reg16, r2: Reg16){
         let val1: u16 = self.reg.get_reg16(r1);
@@ -794,253 +803,75 @@ impl CPU {
         let sum: u16 = val1.wrapping_add(val2);
         self.reg.set_reg16(r1, sum);
 
-
+        //flags - 0 H C, carries out of bit 11 and bit 15
         self.reg.set_n(false);
-        if (sum & 0xF00) > (val1 & 0xF00){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if sum < val1 {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
+        self.reg.set_h((val1 & 0x0FFF) + (val2 & 0x0FFF) > 0x0FFF);
+        self.reg.set_c(sum < val1);
+        // The 16 bit add goes through the 8 bit ALU twice
         self.clock_tick();
-        self.clock_tick();
-    }   
+    }
     fn ADD_r8_n8(&mut self, r: Reg8){
         //Z 0 H C
-        let n8: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
+        let n8: u8 = self.fetch();
         let val: u8 = self.reg.get_reg8(r);
-        let sum: u8 = val.wrapping_add(n8);
+        let sum: u8 = self.alu_add(val, n8, false);
         self.reg.set_reg8(r, sum);
-
-        self.reg.set_z(sum == 0);
-        self.reg.set_n(false);
-        if (sum & 0xF0) > (val & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if sum < val {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.clock_tick();  
     }
     fn ADD_sp_e8(&mut self){
-        let e8: i8 = self.bus.read(self.reg.pc) as i8;
-        self.reg.pc += 1;
+        let e8: i8 = self.fetch() as i8;
 
-        let sum: u16 = self.reg.sp.wrapping_add_signed(e8 as i16);
-        self.reg.set_z(false);
-        self.reg.set_n(false);
-        if (sum & 0xF0) > (self.reg.sp & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if sum < self.reg.sp {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.sp = sum;
-        self.clock_tick();
-        self.clock_tick();
+        self.reg.sp = self.sp_add_e8(e8);
         self.clock_tick();
         self.clock_tick();
     }
 
     fn ADC_A_r8(&mut self, r: Reg8){
-        let cf: u8 = if self.reg.get_c() {1} else {0};
-        let val: u8 = self.reg.a.wrapping_add(cf).wrapping_add(self.reg.get_reg8(r));
-
-        self.reg.set_z(val == 0);
-        self.reg.set_n(false);
-        if (val & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if val < self.reg.a {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = val;
-        self.clock_tick();
+        let carry: bool = self.reg.get_c();
+        self.reg.a = self.alu_add(self.reg.a, self.reg.get_reg8(r), carry);
     }
 
     fn ADC_A_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
 
-        let cf: u8 = if self.reg.get_c() {1} else {0};
-        let val: u8 = self.reg.a.wrapping_add(cf).wrapping_add(data);
-        self.reg.set_z(val == 0);
-        self.reg.set_n(false);
-        if (val & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if val < self.reg.a {
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = val;
-        self.clock_tick();
+        let carry: bool = self.reg.get_c();
+        self.reg.a = self.alu_add(self.reg.a, data, carry);
     }
 
-    fn SUB(&mut self, r: Reg8){
-        let res: u8 = self.reg.a.wrapping_sub(self.reg.get_reg8(r));
+    fn ADC_A_n8(&mut self){
+        let n8: u8 = self.fetch();
 
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick();
+        let carry: bool = self.reg.get_c();
+        self.reg.a = self.alu_add(self.reg.a, n8, carry);
+    }
+
+    fn SUB(&mut self, r: Reg8){
+        self.reg.a = self.alu_sub(self.reg.a, self.reg.get_reg8(r), false);
     }
     fn SUB_D8(&mut self){
-        let d8: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
-        let res: u8 = self.reg.a.wrapping_sub(d8);
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick();
+        let d8: u8 = self.fetch();
+        self.reg.a = self.alu_sub(self.reg.a, d8, false);
     }
 
     fn SUB_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
 
-        let res: u8 = self.reg.a.wrapping_sub(data);
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick()
+        self.reg.a = self.alu_sub(self.reg.a, data, false);
     }
 
     fn SBC(&mut self, r: Reg8){
-        let cf: u8 = if self.reg.get_c() {1} else {0};
-        let res: u8 = self.reg.a.wrapping_sub(self.reg.get_reg8(r))
-                                    .wrapping_sub(cf);
-
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick();
+        let carry: bool = self.reg.get_c();
+        self.reg.a = self.alu_sub(self.reg.a, self.reg.get_reg8(r), carry);
     }
     fn SBC_D8(&mut self){
-        let d8: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        let cf: u8 = if self.reg.get_c() {1} else {0};
-        let res: u8 = self.reg.a.wrapping_sub(d8).wrapping_sub(cf);
-
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick();
+        let d8: u8 = self.fetch();
+        let carry: bool = self.reg.get_c();
+        self.reg.a = self.alu_sub(self.reg.a, d8, carry);
     }
 
     fn SBC_HL(&mut self){
-        let cf: u8 = if self.reg.get_c() {1} else {0};
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
-        let res: u8 = self.reg.a.wrapping_sub(data).wrapping_sub(cf);
-
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        if (res & 0xF0) < (self.reg.a & 0xF0){
-            self.reg.set_h(true);
-        }
-        else{
-            self.reg.set_h(false);
-        }
-        if (res & 0xF0) > (self.reg.a & 0xF0){
-            self.reg.set_c(true);
-        }
-        else{
-            self.reg.set_c(false);
-        }
-        self.reg.a = res;
-        self.clock_tick()
+        let carry: bool = self.reg.get_c();
+        let data: u8 = self.read(self.reg.get_hl());
+        self.reg.a = self.alu_sub(self.reg.a, data, carry);
     }
 
     fn AND(&mut self, r: Reg8){
@@ -1049,89 +880,72 @@ impl CPU {
         self.reg.set_n(false);
         self.reg.set_h(true);
         self.reg.set_c(false);
-        self.clock_tick();
     }
     fn AND_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
         self.reg.a = self.reg.a & data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
         self.reg.set_h(true);
         self.reg.set_c(false);
-        self.clock_tick();
     }
     fn AND_D8(&mut self){
-        let data: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc = self.reg.pc + 1;
-        self.clock_tick();
+        let data: u8 = self.fetch();
         self.reg.a = self.reg.a & data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
         self.reg.set_h(true);
         self.reg.set_c(false);
-        self.clock_tick();
     }
 
     fn XOR(&mut self, r: Reg8){
         self.reg.a = self.reg.a ^ self.reg.get_reg8(r);
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
     fn XOR_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
         self.reg.a = self.reg.a ^  data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
     fn XOR_D8(&mut self){
-        let data: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
+        let data: u8 = self.fetch();
         self.reg.a = self.reg.a ^  data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
 
     fn OR(&mut self, r: Reg8){
         self.reg.a = self.reg.a | self.reg.get_reg8(r);
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
 
     fn OR_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
         self.reg.a = self.reg.a |  data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
 
     fn OR_D8(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
+        let data: u8 = self.fetch();
         self.reg.a = self.reg.a |  data;
         self.reg.set_z(self.reg.a == 0);
         self.reg.set_n(false);
-        self.reg.set_h(true);
+        self.reg.set_h(false);
         self.reg.set_c(false);
-        self.clock_tick();
     }
     fn RLCA(&mut self){
         let mut a: u8 = self.reg.a;
@@ -1143,61 +957,36 @@ impl CPU {
         self.reg.set_n(false);
         self.reg.set_h(false);
         self.reg.set_c(c);
-        self.clock_tick();
     }
 
     fn CP(&mut self, r: Reg8){
         let val: u8 = self.reg.get_reg8(r);
-        let res: u8 = self.reg.a - val;
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        //watch this line while testing (854)
-        self.reg.set_h(((self.reg.a & 0x0F) - (val & 0x0F)) < (0 as u8));
-        self.reg.set_c(res < (0 as u8));
-        self.clock_tick();
+        // Subtract for the flags only
+        self.alu_sub(self.reg.a, val, false);
     }
     fn CP_HL(&mut self){
-        let data: u8 = self.bus.read(self.reg.get_hl());
-        self.clock_tick();
-        let res: u8 = self.reg.a - data;
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        //watch this line while testing (854)
-        self.reg.set_h(((self.reg.a & 0x0F) - (data & 0x0F)) < (0 as u8));
-        self.reg.set_c(res < (0 as u8));
-        self.clock_tick();
+        let data: u8 = self.read(self.reg.get_hl());
+        self.alu_sub(self.reg.a, data, false);
     }
 
     fn CP_d8(&mut self){
-        let data: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        self.clock_tick();
-        let res: u8 = self.reg.a.wrapping_sub(data);
-        self.reg.set_z(res == 0);
-        self.reg.set_n(true);
-        //watch this line while testing (854)
-        self.reg.set_h(((self.reg.a & 0x0F).wrapping_sub((data & 0x0F)) < (0 as u8)));
-        self.reg.set_c(res < (0 as u8));
-        self.clock_tick();
+        let data: u8 = self.fetch();
+        self.alu_sub(self.reg.a, data, false);
     }
 
     fn RET(&mut self, cond: Cond){
-        self.clock_tick();
-        self.clock_tick();
-        match cond {
-            Cond::Z => {if !self.reg.get_z() {return;} },
-            Cond::C => {if !self.reg.get_c() {return;}},
-            Cond::NC => {if self.reg.get_c(){return;}},
-            Cond::NZ => {if self.reg.get_z(){return;}}
-            Cond::NONE=> {}
+        if !matches!(cond, Cond::NONE) {
+            // Conditional returns spend a cycle checking the flags
+            self.clock_tick();
+        }
+        if !self.check_cond(cond) {
+            return;
         }
 
         let lo: u8 = self.stkpop();
         let hi: u8 = self.stkpop();
         self.reg.pc = ((hi as u16) << 8) | lo as u16 ;
         self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
     }
     
     fn RETI(&mut self){
@@ -1207,24 +996,19 @@ impl CPU {
 
     fn POP(&mut self, r: Reg16){
         let lo: u8 = self.stkpop();
-        self.clock_tick();
         let hi: u8 = self.stkpop();
-        self.clock_tick();
         let data: u16 = ((hi as u16) << 8) | lo as u16 ;
+        // set_af drops the lower nibble of F
         self.reg.set_reg16(r, data);
-        self.clock_tick();
     }
 
     fn PUSH(&mut self, r: Reg16){
         let val: u16 = self.reg.get_reg16(r);
         let hi: u8 = ((val & 0xFF00) >> 8) as u8;
         let lo: u8 = (val & 0x00FF) as u8;
+        self.clock_tick();
         self.stkpush(hi);
         self.stkpush(lo);
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
     }
     fn RRCA(&mut self){
         let mut a: u8 = self.reg.a;
@@ -1237,33 +1021,33 @@ impl CPU {
         self.reg.set_n(false);
         self.reg.set_h(false);
         self.reg.set_c(c); 
-        self.clock_tick();
     }
     
+    /// STOP is followed by a byte that is skipped.
+    /// With a CGB speed switch armed in KEY1 it switches speed and carries on,
+    /// otherwise DIV is reset and the CPU and clock stop until a button is pressed
     fn STOP(&mut self){
-        self.reg.pc += 1;
-        self.clock_tick();
-        self.clock_tick();
-        std::process::exit(2);
+        self.reg.pc = self.reg.pc.wrapping_add(1);
+        if self.bus.stop() {
+            self.stopped = true;
+        }
     }
 
     fn RLA(&mut self){
         let mut a: u8 = self.reg.a;
-        let MSB: bool = if (a >> 7) != 0 {true} else {false};
+        let msb: bool = (a >> 7) != 0;
         let c: u8 = if self.reg.get_c() {1} else {0};
-        a *= 2;
-        a = a + c;
+        a = (a << 1) | c;
         self.reg.a = a;
         self.reg.set_z(false);
         self.reg.set_n(false);
         self.reg.set_h(false);
-        self.reg.set_c(MSB);
-        self.clock_tick();
+        self.reg.set_c(msb);
     }
 
     fn RRA(&mut self){
         let mut a: u8 = self.reg.a;
-        let LSB: bool = if ( (a >> 7) & 1) != 0 {true} else {false};
+        let lsb: bool = (a & 1) != 0;
         let c: u8 = if self.reg.get_c() {1} else {0};
         a = a >> 1;
         a = a | (c << 7);
@@ -1271,21 +1055,18 @@ impl CPU {
         self.reg.set_z(false);
         self.reg.set_n(false);
         self.reg.set_h(false);
-        self.reg.set_c(LSB);
-        self.clock_tick();
+        self.reg.set_c(lsb);
     }
 
     fn CPL(&mut self){
         self.reg.a = !self.reg.a;
         self.reg.set_n(true);
         self.reg.set_h(true);
-        self.clock_tick();
     }
     fn SCF(&mut self){
         self.reg.set_n(false);
         self.reg.set_h(false);
         self.reg.set_c(true);
-        self.clock_tick();
     }
 
     fn CCF(&mut self){
@@ -1293,327 +1074,690 @@ impl CPU {
         self.reg.set_h(false);
         let c: u8 = if self.reg.get_c() {1} else {0};
         self.reg.set_c((c^1) != 0);
-        self.clock_tick();
     }
     
+    /// Halts until an interrupt is both requested and enabled, whether IME is set or not.
+    /// If one already is while IME is off the CPU doesn't halt at all, and hits the HALT bug instead
     fn HALT(&mut self){
+        if !self.ime && self.bus.pending_interrupt().is_some() {
+            self.halt_bug = true;
+            return;
+        }
         self.halted = true;
-        self.clock_tick();
+    }
+
+    /// The 11 unused opcodes (D3 DB DD E3 E4 EB EC ED F4 FC FD) hang the CPU,
+    /// interrupts are no longer serviced and it never fetches again
+    fn ILLEGAL(&mut self, opcode: u8){
+        self.locked = true;
+        self.events.push(Event::Lockup { opcode, pc: self.reg.pc.wrapping_sub(1) });
     }
 
     fn JR_e8(&mut self){
-        let e8: i8 = self.bus.read(self.reg.pc) as i8;
-        self.reg.pc += 1;
-        self.clock_tick();
+        let e8: i8 = self.fetch() as i8;
         self.reg.pc = self.reg.pc.wrapping_add_signed(e8 as i16);
         self.clock_tick();
-        self.clock_tick();
     }
 
     fn JR(&mut self, cond: Cond){
-        let e8: i8 = self.bus.read(self.reg.pc) as i8;
-        self.reg.pc += 1;
-        self.clock_tick();
-        self.clock_tick();
-        match cond {
-            Cond::Z => {if !self.reg.get_z() {return;} },
-            Cond::C => {if !self.reg.get_c() {return;}},
-            Cond::NC => {if self.reg.get_c(){return;}},
-            Cond::NZ => {if self.reg.get_z(){return;}},
-            Cond::NONE=> {},
+        let e8: i8 = self.fetch() as i8;
+        if !self.check_cond(cond) {
+            return;
         }
         self.reg.pc = self.reg.pc.wrapping_add_signed(e8 as i16);
-       
-        self.clock_tick();  
+        self.clock_tick();
     }
 
     fn JP_a16(&mut self, cond: Cond){
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
-        match cond {
-            Cond::Z => {if !self.reg.get_z() {return;} },
-            Cond::C => {if !self.reg.get_c() {return;}},
-            Cond::NC => {if self.reg.get_c(){return;}},
-            Cond::NZ => {if self.reg.get_z(){return;}},
-            Cond::NONE=> {},
+        // The address is always read, even when the jump isn't taken
+        let lo: u8 = self.fetch();
+        let hi: u8 = self.fetch();
+        if !self.check_cond(cond) {
+            return;
         }
-        let lo: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        let hi: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
         let addr:u16 = ((hi as u16) << 8) | lo as u16 ;
         self.reg.pc = addr;
-        self.clock_tick(); 
+        self.clock_tick();
     }
 
     fn JP_HL(&mut self){
         self.reg.pc = self.reg.get_hl();
-        self.clock_tick();
     }
 
     fn CALL(&mut self, cond: Cond){
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
-        match cond {
-            Cond::Z => {if !self.reg.get_z() {return;} },
-            Cond::C => {if !self.reg.get_c() {return;}},
-            Cond::NC => {if self.reg.get_c(){return;}},
-            Cond::NZ => {if self.reg.get_z(){return;}},
-            Cond::NONE=> {},
+        // The address is always read, even when the call isn't made
+        let lo: u8 = self.fetch();
+        let hi: u8 = self.fetch();
+        if !self.check_cond(cond) {
+            return;
         }
+        // Return address is the instruction after the call
         let pc_hi: u8 = ((self.reg.pc & 0xFF00) >> 8) as u8;
         let pc_lo: u8 = (self.reg.pc & 0x00FF) as u8;
+        self.clock_tick();
         self.stkpush(pc_hi);
         self.stkpush(pc_lo);
-        let lo: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
-        let hi: u8 = self.bus.read(self.reg.pc);
-        self.reg.pc += 1;
         let addr: u16 = ((hi as u16) << 8) | lo as u16 ;
         self.reg.pc = addr;
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
     }
 
     fn RST(&mut self, lo: u8){
         let pc_hi: u8 = ((self.reg.pc & 0xFF00) >> 8) as u8;
         let pc_lo: u8 = (self.reg.pc & 0x00FF) as u8;
+        self.clock_tick();
         self.stkpush(pc_hi);
         self.stkpush(pc_lo);
         self.reg.pc = lo as u16;
-        self.clock_tick();
-        self.clock_tick();
-        self.clock_tick();
+    }
+
+    /// Whether a conditional jump/call/return is taken
+    fn check_cond(&self, cond: Cond) -> bool{
+        match cond {
+            Cond::Z => self.reg.get_z(),
+            Cond::C => self.reg.get_c(),
+            Cond::NC => !self.reg.get_c(),
+            Cond::NZ => !self.reg.get_z(),
+            Cond::NONE => true,
+        }
+    }
+
+    /// 8 bit add with carry in, flags Z 0 H C
+    fn alu_add(&mut self, a: u8, b: u8, carry: bool) -> u8{
+        let c: u8 = if carry {1} else {0};
+        let res: u16 = a as u16 + b as u16 + c as u16;
+        self.reg.set_z(res as u8 == 0);
+        self.reg.set_n(false);
+        self.reg.set_h((a & 0x0F) + (b & 0x0F) + c > 0x0F);
+        self.reg.set_c(res > 0xFF);
+        return res as u8;
+    }
+
+    /// 8 bit subtract with borrow in, flags Z 1 H C
+    fn alu_sub(&mut self, a: u8, b: u8, carry: bool) -> u8{
+        let c: u8 = if carry {1} else {0};
+        let res: u8 = a.wrapping_sub(b).wrapping_sub(c);
+        self.reg.set_z(res == 0);
+        self.reg.set_n(true);
+        self.reg.set_h((a & 0x0F) < (b & 0x0F) + c);
+        self.reg.set_c((a as u16) < b as u16 + c as u16);
+        return res;
+    }
+
+    /// SP plus a signed byte, flags 0 0 H C from the unsigned add of the low byte
+    fn sp_add_e8(&mut self, e8: i8) -> u16{
+        let sp: u16 = self.reg.sp;
+        let e: u16 = e8 as u8 as u16;
+        self.reg.set_z(false);
+        self.reg.set_n(false);
+        self.reg.set_h((sp & 0x0F) + (e & 0x0F) > 0x0F);
+        self.reg.set_c((sp & 0xFF) + e > 0xFF);
+        return sp.wrapping_add_signed(e8 as i16);
+    }
+
+    ///only to be used by cb
+    /// operand index: B C D E H L (HL) A
+    fn get_reg_cb(&mut self, op: u8) -> u8{
+        match op{
+            0 => self.reg.b,
+            1 => self.reg.c,
+            2 => self.reg.d,
+            3 => self.reg.e,
+            4 => self.reg.h,
+            5 => self.reg.l,
+            6 => {
+                let val: u8 = self.read(self.reg.get_hl());
+                val
+            },
+            7 => self.reg.a,
+            _=>panic!("Invalid cb reg")
+        }
     }
 
     ///only to be used by cb
     fn set_reg_cb(&mut self, op: u8, val: u8){
         match op{
-            0 => self.reg.a = val,
+            0 => self.reg.b = val,
             1 => self.reg.c = val,
             2 => self.reg.d = val,
             3 => self.reg.e = val,
             4 => self.reg.h = val,
             5 => self.reg.l = val,
-            6 => self.bus.write(self.reg.get_hl(), val),
+            6 => {
+                self.write(self.reg.get_hl(), val);
+            },
             7 => self.reg.a = val,
             _=>panic!("Invalid cb reg")
         };
     }
 
+    /// 0xCB prefixed instructions
+    /// bits 7-6: 00 rotate/shift group, 01 BIT, 10 RES, 11 SET
+    /// bits 5-3: bit number, or which rotate/shift for group 00
+    /// bits 2-0: operand B C D E H L (HL) A
+    /// Timing: 2 machine cycles, (HL) adds 1 for the read and 1 for the write back (BIT only reads)
     fn CB(&mut self){
 
-        //B C D E H L HL A
-
         //byte to decode
-        let cb_op: u8 = self.bus.read(self.reg.pc);
+        let cb_op: u8 = self.fetch();
         //value being used (certain operations)
         let bit_val: u8 = (cb_op >> 3) & 0b111;
         //opcode of the instruction RLC RRC RL RR SLA SRA SWAP SRL BIT RES SET
         let bit_op: u8 = (cb_op >> 6) & 0b11;
-        self.reg.pc += 1;
-        self.clock_tick();
+        let operand: u8 = cb_op & 0b111;
         //gets value at desired cb reg
-        let val: u8 = match cb_op & 0b111 {
-            0 => self.reg.get_reg8(Reg8::A),
-            1 => self.reg.get_reg8(Reg8::C),
-            2 => self.reg.get_reg8(Reg8::D),
-            3 => self.reg.get_reg8(Reg8::E),
-            4 => self.reg.get_reg8(Reg8::H),
-            5 => self.reg.get_reg8(Reg8::L),
-            6 => self.bus.read(self.reg.get_hl()),
-            7 => self.reg.get_reg8(Reg8::A),
-            _=>panic!("Invalid cb reg")
-        };
-    
-        if (cb_op & 0b111) == 6{
-            //HL case
-            self.clock_tick();
-            self.clock_tick();
-        }
-        let cf: u8 =  if self.reg.get_c() {1} else {0};
+        let val: u8 = self.get_reg_cb(operand);
+
         match bit_op {
             1 => {
                 //BIT
                 self.reg.set_z( val & (1 << bit_val) == 0);
                 self.reg.set_n(false);
                 self.reg.set_h(true);
-                self.clock_tick();
-                return;
             },
             2 => {
                 //RES
                 let new_val: u8 = val & !(1 << bit_val);
-                self.set_reg_cb(cb_op & 0b111 , new_val);
-                self.clock_tick();
-                if (cb_op & 0b111) == 6{
-                    //HL case
-                    self.clock_tick();
-                }
-                return;
+                self.set_reg_cb(operand, new_val);
             },
             3 => {
                 //SET
                 let new_val: u8 = val | (1 << bit_val);
-                self.set_reg_cb(cb_op & 0b111 , new_val);
-                self.clock_tick();
-                return;
+                self.set_reg_cb(operand, new_val);
             },
-            _=> match bit_val {
-                0 => {
+            _=> {
+                let cf: u8 =  if self.reg.get_c() {1} else {0};
+                let (res, carry): (u8, bool) = match bit_val {
                     //RLC
-                    let mut setC: bool = false;
-                    let mut res: u8 = (val << 1) & 0xFF;
-                    if val & (1 << 7) != 0 {
-                        res |= 1;
-                        setC = true;
-                    }
-                    self.set_reg_cb(cb_op & 0b111, res);
-                    self.reg.set_z(res == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(setC);
-                    self.clock_tick();
-                    return;
-                },
-                1 => {
+                    0 => (val.rotate_left(1), val & 0x80 != 0),
                     //RRC
-                    let old: u8 = val;
-                    let mut new: u8 = val >> 1;
-                    new |= old << 7;
-        
-                    self.set_reg_cb(cb_op & 0b11, new);
-                    self.reg.set_z(new == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c((old & 1) != 0);
-                    self.clock_tick();
-                    return;
-                },
-                2 => {
+                    1 => (val.rotate_right(1), val & 1 != 0),
                     //RL
-                    let old: u8 = val;
-                    let mut new: u8 =  old << 1;
-                    new |= cf;
-                    self.set_reg_cb(cb_op & 0b111, new);
-                    self.reg.set_z(new == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(!!(old & 0x80) != 0);
-                    self.clock_tick();
-                    return;
-                },
-                3 => {
+                    2 => ((val << 1) | cf, val & 0x80 != 0),
                     //RR
-                    let old: u8 = val;
-                    let mut new: u8 = old >> 1;
-                    new |= cf << 7;
-                    self.set_reg_cb(cb_op & 0b111, new);
-                    self.reg.set_z(new == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(old & 1 != 0);
-                    self.clock_tick();
-                    return;
-                },
-                4 => {
+                    3 => ((val >> 1) | (cf << 7), val & 1 != 0),
                     //SLA
-                    let old: u8 = val;
-                    let mut new: u8 =  old << 1;
-
-                    self.set_reg_cb(cb_op & 0b111, new);
-                    self.reg.set_z(new == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(!!(old & 0x80) != 0);
-                    self.clock_tick();
-                    return;
-                },
-                5 => {
-                    //SRA
-                    let u: u8 = ((val as i8) >> 1) as u8;
-                    self.set_reg_cb(cb_op & 0b111, u);
-        
-                    self.reg.set_z(u != 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(val & 1 != 0);  
-                    self.clock_tick();
-                    return;
-                },
-                6 => {
+                    4 => (val << 1, val & 0x80 != 0),
+                    //SRA - bit 7 stays
+                    5 => ((val >> 1) | (val & 0x80), val & 1 != 0),
                     //SWAP
-                    let new: u8 = ((val & 0xF0) >> 4) | ((val & 0xF) << 4);
-                    self.set_reg_cb(cb_op & 0b111, new);
-                    self.reg.set_z(new == 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(false);
-                    self.clock_tick();
-                    return;
-                },
-                7 => {
+                    6 => (((val & 0xF0) >> 4) | ((val & 0xF) << 4), false),
                     //SRL
-                    let u: u8 = val >> 1;
-                    self.set_reg_cb(cb_op & 0b111, u);
-        
-                    self.reg.set_z(u != 0);
-                    self.reg.set_n(false);
-                    self.reg.set_h(false);
-                    self.reg.set_c(val & 1 != 0);   
-                    self.clock_tick();      
-                }
-                _=> panic!("Invalid CB")
+                    7 => (val >> 1, val & 1 != 0),
+                    _=> panic!("Invalid CB")
+                };
+                self.set_reg_cb(operand, res);
+                self.reg.set_z(res == 0);
+                self.reg.set_n(false);
+                self.reg.set_h(false);
+                self.reg.set_c(carry);
             }
         }
     }
 
     fn DAA(&mut self){
+        let mut a: u8 = self.reg.a;
+        let mut carry: bool = self.reg.get_c();
         if !self.reg.get_n() {
-            if self.reg.get_c() || self.reg.a > 0x99{
-                self.reg.a = self.reg.a + 0x60;
-                self.reg.set_c(true);
+            // after an addition, adjust if (half-)carry occurred or if result is out of bounds
+            if carry || a > 0x99{
+                a = a.wrapping_add(0x60);
+                carry = true;
             }
-            if self.reg.get_h() || ((self.reg.a & 0x0f) > 0x09){
-                self.reg.a = self.reg.a + 0x6;
+            if self.reg.get_h() || ((a & 0x0f) > 0x09){
+                a = a.wrapping_add(0x6);
             }
         }
         else{
-            if self.reg.get_c() {
-                self.reg.a = self.reg.a - 0x60;
+            // after a subtraction, only adjust if (half-)carry occurred
+            if carry {
+                a = a.wrapping_sub(0x60);
             }
             if self.reg.get_h() {
-                self.reg.a = self.reg.a - 0x6;
+                a = a.wrapping_sub(0x6);
             }
         }
-        self.reg.set_z( self.reg.a == 0);
+        self.reg.a = a;
+        self.reg.set_z(a == 0);
         self.reg.set_h(false);
+        self.reg.set_c(carry);
 
-        self.clock_tick();
     }
 
     fn DI(&mut self){
         self.ime = false;
-        self.clock_tick();
     }
     
     fn EI(&mut self){
-        self.ime = true;
-        self.clock_tick();
+        self.ime_delay = true;
     }
 
     fn stkpush(&mut self, data: u8){
         self.reg.sp = self.reg.sp.wrapping_sub(1) ;
-        self.bus.write(self.reg.sp, data);
+        self.write(self.reg.sp, data);
     }
 
     fn stkpop(&mut self)->u8{
 
-        let val: u8 = self.bus.read(self.reg.sp);
+        let val: u8 = self.read(self.reg.sp);
         self.reg.sp = self.reg.sp.wrapping_add(1);
         return val;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Model;
+    use crate::cart::Cart;
+    use crate::io::IO;
+    use crate::ppu::{GPU, Renderer};
+    use std::fs::File;
+
+    /// Test programs run from work RAM, no cartridge needed
+    const PROGRAM: u16 = 0xC000;
+    /// Operand address of the (HL) instructions
+    const HL: u16 = 0xD000;
+    const Z: u8 = 0x80;
+    const N: u8 = 0x40;
+    const H: u8 = 0x20;
+    const C: u8 = 0x10;
+
+    /// CPU about to run `program`, logging to a scratch file instead of log.txt
+    fn cpu_with(program: &[u8]) -> CPU {
+        return cpu_on(Model::Dmg, program);
+    }
+
+    fn cpu_on(model: Model, program: &[u8]) -> CPU {
+        let bus: Bus = Bus::new(Cart::new(), IO::new(48000), GPU::new(Renderer::Scanline), model);
+        let file: File = File::create(std::env::temp_dir().join("gb_at2_cpu_test.log")).unwrap();
+        let mut cpu: CPU = CPU {
+            reg: Registers::new(),
+            ime: false,
+            ime_delay: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            bus,
+            cycles: 0,
+            log: Logger {log: file},
+            events: Vec::new(),
+        };
+        for (i, byte) in program.iter().enumerate() {
+            cpu.bus.write(PROGRAM + i as u16, *byte);
+        }
+        cpu.reg.pc = PROGRAM;
+        return cpu;
+    }
+
+    /// Run CB `op` on `value` with F starting as `flags`, returns the operand afterwards,
+    /// F and the machine cycles taken
+    fn cb(op: u8, value: u8, flags: u8) -> (u8, u8, u64) {
+        let mut cpu: CPU = cpu_with(&[0xCB, op]);
+        cpu.reg.set_hl(HL);
+        cpu.set_reg_cb(op & 0b111, value);
+        cpu.reg.f = flags;
+        let start: u64 = cpu.cycles;
+        cpu.step();
+        let cycles: u64 = cpu.cycles - start;
+        return (cpu.get_reg_cb(op & 0b111), cpu.reg.f, cycles);
+    }
+
+    /// Operand bits for B, (HL) and A
+    const OPERANDS: [u8; 3] = [0, 6, 7];
+
+    #[test]
+    fn cb_rotates_and_shifts() {
+        // (op, value, carry in, result, flags): N and H always end up clear
+        let cases: [(u8, u8, bool, u8, u8); 18] = [
+            (0x00, 0x85, false, 0x0B, C),     // RLC
+            (0x00, 0x00, true, 0x00, Z),
+            (0x08, 0x01, false, 0x80, C),     // RRC
+            (0x08, 0x00, true, 0x00, Z),
+            (0x10, 0x80, false, 0x00, Z | C), // RL
+            (0x10, 0x11, true, 0x23, 0),
+            (0x18, 0x01, false, 0x00, Z | C), // RR
+            (0x18, 0x10, true, 0x88, 0),
+            (0x20, 0x80, false, 0x00, Z | C), // SLA
+            (0x20, 0x41, true, 0x82, 0),
+            (0x28, 0x81, false, 0xC0, C),     // SRA
+            (0x28, 0x01, false, 0x00, Z | C),
+            (0x28, 0x80, true, 0xC0, 0),
+            (0x30, 0xF1, true, 0x1F, 0),      // SWAP
+            (0x30, 0x00, true, 0x00, Z),
+            (0x38, 0x81, false, 0x40, C),     // SRL
+            (0x38, 0x01, false, 0x00, Z | C),
+            (0x38, 0x80, true, 0x40, 0),
+        ];
+        for (op, value, carry, result, flags) in cases {
+            for operand in OPERANDS {
+                let start: u8 = N | H | if carry {C} else {0};
+                let cycles: u64 = if operand == 6 {4} else {2};
+                assert_eq!(cb(op | operand, value, start), (result, flags, cycles), "CB {:02X} on {:02X}", op | operand, value);
+            }
+        }
+    }
+
+    #[test]
+    fn cb_bit() {
+        // (op, value, Z): Z is set for a clear bit, N cleared, H set, C and the value untouched
+        let cases: [(u8, u8, bool); 4] = [
+            (0x78, 0x80, false), // BIT 7
+            (0x78, 0x7F, true),
+            (0x40, 0xFE, true),  // BIT 0
+            (0x40, 0x01, false),
+        ];
+        for (op, value, zero) in cases {
+            for operand in OPERANDS {
+                for carry in [0, C] {
+                    let flags: u8 = if zero {Z} else {0} | H | carry;
+                    let cycles: u64 = if operand == 6 {3} else {2};
+                    assert_eq!(cb(op | operand, value, N | carry), (value, flags, cycles), "CB {:02X} on {:02X}", op | operand, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cb_res_and_set() {
+        for operand in OPERANDS {
+            let cycles: u64 = if operand == 6 {4} else {2};
+            // RES 7 and SET 3 leave the flags alone
+            assert_eq!(cb(0xB8 | operand, 0xFF, Z | C), (0x7F, Z | C, cycles));
+            assert_eq!(cb(0xD8 | operand, 0x00, N | H), (0x08, N | H, cycles));
+        }
+    }
+
+    /// Packed BCD of a number below 100
+    fn bcd(n: u8) -> u8 {
+        return (n / 10) << 4 | n % 10;
+    }
+
+    #[test]
+    fn daa_after_add_and_sub() {
+        for (program, subtract) in [([0x80, 0x27], false), ([0x90, 0x27], true)] {
+            // ADD A,B or SUB A,B then DAA, for every pair of two digit BCD numbers
+            let mut cpu: CPU = cpu_with(&program);
+            for a in 0..100u8 {
+                for b in 0..100u8 {
+                    cpu.reg.pc = PROGRAM;
+                    cpu.reg.a = bcd(a);
+                    cpu.reg.b = bcd(b);
+                    cpu.reg.f = 0;
+                    cpu.step();
+                    cpu.step();
+                    let (result, carry): (u8, bool) = if subtract {
+                        ((a + 100 - b) % 100, a < b)
+                    } else {
+                        ((a + b) % 100, a + b >= 100)
+                    };
+                    let flags: u8 = if result == 0 {Z} else {0} | if subtract {N} else {0} | if carry {C} else {0};
+                    assert_eq!((cpu.reg.a, cpu.reg.f), (bcd(result), flags), "{} {} {}", a, if subtract {"-"} else {"+"}, b);
+                }
+            }
+        }
+    }
+
+    /// (SP, e8, result, flags): H and C come from the unsigned add to the low byte, Z and N are cleared
+    const SP_E8_CASES: [(u16, u8, u16, u8); 6] = [
+        (0x00FF, 0x01, 0x0100, H | C),
+        (0x000F, 0x01, 0x0010, H),
+        (0x0000, 0xFF, 0xFFFF, 0),
+        (0x0001, 0xFF, 0x0000, H | C),
+        (0xFFF8, 0x08, 0x0000, H | C),
+        (0x1234, 0x80, 0x11B4, 0),
+    ];
+
+    #[test]
+    fn add_sp_e8() {
+        for (sp, e8, result, flags) in SP_E8_CASES {
+            let mut cpu: CPU = cpu_with(&[0xE8, e8]);
+            cpu.reg.sp = sp;
+            cpu.reg.f = Z | N | H | C;
+            cpu.step();
+            assert_eq!((cpu.reg.sp, cpu.reg.f, cpu.cycles), (result, flags, 4), "SP {:04X} + {:02X}", sp, e8);
+        }
+    }
+
+    #[test]
+    fn ld_hl_sp_e8() {
+        for (sp, e8, result, flags) in SP_E8_CASES {
+            let mut cpu: CPU = cpu_with(&[0xF8, e8]);
+            cpu.reg.sp = sp;
+            cpu.reg.f = Z | N | H | C;
+            cpu.step();
+            assert_eq!((cpu.reg.get_hl(), cpu.reg.sp, cpu.reg.f, cpu.cycles), (result, sp, flags, 3), "SP {:04X} + {:02X}", sp, e8);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes_lock_up() {
+        for opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            let mut cpu: CPU = cpu_with(&[opcode]);
+            cpu.run();
+            assert_eq!(cpu.take_events(), vec![Event::Lockup { opcode, pc: PROGRAM }]);
+            // A pending, enabled interrupt isn't serviced and nothing more is fetched, but time goes on
+            cpu.ime = true;
+            cpu.bus.write(0xFFFF, 0x01);
+            cpu.bus.write(0xFF0F, 0x01);
+            let (sp, cycles): (u16, u64) = (cpu.reg.sp, cpu.cycles);
+            for _ in 0..10 {
+                cpu.run();
+            }
+            assert_eq!((cpu.reg.pc, cpu.reg.sp, cpu.cycles), (PROGRAM + 1, sp, cycles + 10), "opcode {:02X}", opcode);
+            assert!(cpu.take_events().is_empty());
+        }
+    }
+
+    #[test]
+    fn adc_half_carry_includes_carry_in() {
+        // (A, B, carry in, result, flags) for ADC A,B
+        let cases: [(u8, u8, bool, u8, u8); 5] = [
+            (0x0F, 0x00, true, 0x10, H),
+            (0x0E, 0x01, true, 0x10, H),
+            (0x0E, 0x00, true, 0x0F, 0),
+            (0xFF, 0x00, true, 0x00, Z | H | C),
+            (0x0F, 0x00, false, 0x0F, 0),
+        ];
+        for (a, b, carry, result, flags) in cases {
+            let mut cpu: CPU = cpu_with(&[0x88]);
+            cpu.reg.a = a;
+            cpu.reg.b = b;
+            cpu.reg.f = if carry {C} else {0};
+            cpu.step();
+            assert_eq!((cpu.reg.a, cpu.reg.f), (result, flags), "{:02X} + {:02X} + {}", a, b, carry);
+        }
+    }
+
+    #[test]
+    fn sbc_half_carry_includes_carry_in() {
+        // (A, B, carry in, result, flags) for SBC A,B
+        let cases: [(u8, u8, bool, u8, u8); 5] = [
+            (0x10, 0x00, true, 0x0F, N | H),
+            (0x1F, 0x0F, true, 0x0F, N | H),
+            (0x00, 0x00, true, 0xFF, N | H | C),
+            (0x01, 0x00, true, 0x00, Z | N),
+            (0x10, 0x00, false, 0x10, N),
+        ];
+        for (a, b, carry, result, flags) in cases {
+            let mut cpu: CPU = cpu_with(&[0x98]);
+            cpu.reg.a = a;
+            cpu.reg.b = b;
+            cpu.reg.f = if carry {C} else {0};
+            cpu.step();
+            assert_eq!((cpu.reg.a, cpu.reg.f), (result, flags), "{:02X} - {:02X} - {}", a, b, carry);
+        }
+    }
+
+    /// Request `requested` in IF with `enabled` in IE
+    fn set_interrupts(cpu: &mut CPU, enabled: u8, requested: u8) {
+        cpu.bus.write(0xFFFF, enabled);
+        cpu.bus.write(0xFF0F, requested);
+    }
+
+    /// Return address the last interrupt dispatch pushed
+    fn pushed_pc(cpu: &mut CPU) -> u16 {
+        let lo: u8 = cpu.bus.read(cpu.reg.sp);
+        let hi: u8 = cpu.bus.read(cpu.reg.sp.wrapping_add(1));
+        return u16::from_le_bytes([lo, hi]);
+    }
+
+    #[test]
+    fn ei_delays_ime_by_one_instruction() {
+        // EI, NOP: the NOP still runs before the VBlank interrupt is taken
+        let mut cpu: CPU = cpu_with(&[0xFB, 0x00, 0x00]);
+        set_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.run();
+        assert_eq!(cpu.reg.pc, PROGRAM + 1);
+        assert!(!cpu.ime);
+        cpu.run();
+        assert_eq!(cpu.reg.pc, 0x0040);
+        assert_eq!(pushed_pc(&mut cpu), PROGRAM + 2);
+
+        // EI, DI: IME never gets to dispatch anything
+        let mut cpu: CPU = cpu_with(&[0xFB, 0xF3, 0x00]);
+        set_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.run();
+        cpu.run();
+        cpu.run();
+        assert_eq!(cpu.reg.pc, PROGRAM + 3);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_byte_twice() {
+        // HALT, INC A with IME off and the timer interrupt already pending
+        let mut cpu: CPU = cpu_with(&[0x76, 0x3C, 0x00]);
+        set_interrupts(&mut cpu, 0x04, 0x04);
+        cpu.reg.a = 0;
+        cpu.run();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.pc, PROGRAM + 1);
+        cpu.run();
+        assert_eq!((cpu.reg.a, cpu.reg.pc), (1, PROGRAM + 1));
+        cpu.run();
+        assert_eq!((cpu.reg.a, cpu.reg.pc), (2, PROGRAM + 2));
+    }
+
+    #[test]
+    fn halt_wakes_on_requested_and_enabled_interrupt() {
+        // HALT, INC A with IME off: wakes up and carries on without dispatching
+        let mut cpu: CPU = cpu_with(&[0x76, 0x3C]);
+        set_interrupts(&mut cpu, 0x04, 0x00);
+        cpu.reg.a = 0;
+        cpu.run();
+        assert!(cpu.halted);
+        // Requested but not enabled isn't enough
+        cpu.bus.write(0xFF0F, 0x01);
+        for _ in 0..10 {
+            cpu.run();
+        }
+        assert!(cpu.halted);
+        assert_eq!(cpu.reg.pc, PROGRAM + 1);
+        cpu.bus.write(0xFF0F, 0x04);
+        cpu.run();
+        assert!(!cpu.halted);
+        cpu.run();
+        assert_eq!((cpu.reg.a, cpu.reg.pc), (1, PROGRAM + 2));
+
+        // With IME on the wake-up goes straight into the dispatch, returning after HALT
+        let mut cpu: CPU = cpu_with(&[0x76, 0x3C]);
+        set_interrupts(&mut cpu, 0x04, 0x00);
+        cpu.ime = true;
+        cpu.run();
+        assert!(cpu.halted);
+        cpu.bus.write(0xFF0F, 0x04);
+        cpu.run();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(pushed_pc(&mut cpu), PROGRAM + 1);
+    }
+
+    #[test]
+    fn stop_waits_for_a_button() {
+        // STOP 00, INC A
+        let mut cpu: CPU = cpu_with(&[0x10, 0x00, 0x3C]);
+        cpu.reg.a = 0;
+        for _ in 0..300 {
+            cpu.clock_tick();
+        }
+        assert_ne!(cpu.bus.read(0xFF04), 0);
+        cpu.run();
+        assert!(cpu.stopped());
+        assert_eq!(cpu.reg.pc, PROGRAM + 2);
+        assert_eq!(cpu.bus.read(0xFF04), 0);
+        for _ in 0..10 {
+            cpu.run();
+        }
+        assert!(cpu.stopped());
+        assert_eq!(cpu.reg.a, 0);
+        // Only a pressed button on a selected line ends it
+        cpu.set_button(Button::Start, true);
+        cpu.run();
+        assert!(cpu.stopped());
+        cpu.bus.write(0xFF00, 0x00);
+        cpu.run();
+        assert!(!cpu.stopped());
+        cpu.run();
+        assert_eq!(cpu.reg.a, 1);
+    }
+
+    #[test]
+    fn stop_switches_speed_when_armed() {
+        // STOP 00, INC A on a CGB with KEY1 armed
+        let mut cpu: CPU = cpu_on(Model::CgbE, &[0x10, 0x00, 0x3C]);
+        cpu.reg.a = 0;
+        assert_eq!(cpu.bus.read(0xFF4D), 0x7E);
+        cpu.bus.write(0xFF4D, 0x01);
+        assert_eq!(cpu.bus.read(0xFF4D), 0x7F);
+        cpu.run();
+        assert!(!cpu.stopped());
+        assert_eq!(cpu.bus.read(0xFF4D), 0xFE);
+        cpu.run();
+        assert_eq!((cpu.reg.a, cpu.reg.pc), (1, PROGRAM + 3));
+        // Unarmed it stops like a DMG
+        let mut cpu: CPU = cpu_on(Model::CgbE, &[0x10, 0x00, 0x3C]);
+        cpu.run();
+        assert!(cpu.stopped());
+        assert_eq!(cpu.bus.read(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn dispatch_takes_five_cycles_and_clears_one_request() {
+        // Timer and joypad requested, the timer goes first
+        let mut cpu: CPU = cpu_with(&[0x00]);
+        set_interrupts(&mut cpu, 0x1F, 0x14);
+        cpu.ime = true;
+        let start: u64 = cpu.cycles;
+        cpu.handle_interrupt();
+        assert_eq!(cpu.cycles - start, 5);
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(pushed_pc(&mut cpu), PROGRAM);
+        assert_eq!(cpu.bus.read(0xFF0F), 0xE0 | 0x10);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn dispatch_follows_ie_overwritten_by_the_push() {
+        // With SP at 0x0000 the high byte of PC lands in IE. 0xC4 leaves only the timer enabled
+        let mut cpu: CPU = cpu_with(&[]);
+        set_interrupts(&mut cpu, 0x05, 0x05);
+        cpu.ime = true;
+        cpu.reg.sp = 0x0000;
+        cpu.reg.pc = 0xC400;
+        cpu.handle_interrupt();
+        assert_eq!(cpu.reg.pc, 0x0050);
+        assert_eq!(cpu.bus.read(0xFFFF), 0xC4);
+        assert_eq!(cpu.bus.read(0xFF0F), 0xE0 | 0x01);
+
+        // 0xC0 enables nothing, the dispatch is cancelled and jumps to 0x0000 with IF untouched
+        let mut cpu: CPU = cpu_with(&[]);
+        set_interrupts(&mut cpu, 0x01, 0x01);
+        cpu.ime = true;
+        cpu.reg.sp = 0x0000;
+        cpu.handle_interrupt();
+        assert_eq!(cpu.reg.pc, 0x0000);
+        assert_eq!(cpu.bus.read(0xFF0F), 0xE0 | 0x01);
+        assert!(!cpu.ime);
+    }
+}
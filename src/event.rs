@@ -0,0 +1,18 @@
+////////////////
+/// 
+/// event.rs
+/// 
+/// Things the emulated hardware does that the frontend may want to react to,
+/// but which have no place in the memory map (e.g. a rumble motor spinning up).
+/// Events are queued by the component that raises them and drained by the
+/// frontend through `CPU::take_events`.
+/// 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The cartridge rumble motor was switched on (true) or off (false)
+    Rumble(bool),
+    /// The PPU finished a frame, or a blank frame's worth of time went by with the LCD off
+    Frame,
+    /// The CPU executed an illegal opcode at `pc` and has locked up until reset
+    Lockup { opcode: u8, pc: u16 },
+}
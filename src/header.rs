@@ -0,0 +1,625 @@
+////////////////
+/// 
+/// header.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/The_Cartridge_Header.html - Header layout, codes and checksums
+/// 
+/// Header Layout
+/// 0x0104 - 0x0133 : Nintendo logo
+/// 0x0134 - 0x0143 : Title (0x013F - 0x0142 manufacturer code and 0x0143 CGB flag on newer carts)
+/// 0x0144 - 0x0145 : New licensee code
+/// 0x0146          : SGB flag
+/// 0x0147          : Cartridge type
+/// 0x0148          : ROM size
+/// 0x0149          : RAM size
+/// 0x014A          : Destination code
+/// 0x014B          : Old licensee code
+/// 0x014C          : Mask ROM version number
+/// 0x014D          : Header checksum
+/// 0x014E - 0x014F : Global checksum
+/// 
+use std::fmt;
+
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    Huc3,
+    Huc1,
+    Unknown(u8),
+}
+
+/// Decoded cartridge type byte at 0x0147
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CartType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartType {
+    pub fn from_code(code: u8) -> Self {
+        //                         mapper               ram    battery timer  rumble
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (Mapper::RomOnly,      false, false, false, false),
+            0x01 => (Mapper::Mbc1,         false, false, false, false),
+            0x02 => (Mapper::Mbc1,         true,  false, false, false),
+            0x03 => (Mapper::Mbc1,         true,  true,  false, false),
+            0x05 => (Mapper::Mbc2,         false, false, false, false),
+            0x06 => (Mapper::Mbc2,         false, true,  false, false),
+            0x08 => (Mapper::RomOnly,      true,  false, false, false),
+            0x09 => (Mapper::RomOnly,      true,  true,  false, false),
+            0x0B => (Mapper::Mmm01,        false, false, false, false),
+            0x0C => (Mapper::Mmm01,        true,  false, false, false),
+            0x0D => (Mapper::Mmm01,        true,  true,  false, false),
+            0x0F => (Mapper::Mbc3,         false, true,  true,  false),
+            0x10 => (Mapper::Mbc3,         true,  true,  true,  false),
+            0x11 => (Mapper::Mbc3,         false, false, false, false),
+            0x12 => (Mapper::Mbc3,         true,  false, false, false),
+            0x13 => (Mapper::Mbc3,         true,  true,  false, false),
+            0x19 => (Mapper::Mbc5,         false, false, false, false),
+            0x1A => (Mapper::Mbc5,         true,  false, false, false),
+            0x1B => (Mapper::Mbc5,         true,  true,  false, false),
+            0x1C => (Mapper::Mbc5,         false, false, false, true),
+            0x1D => (Mapper::Mbc5,         true,  false, false, true),
+            0x1E => (Mapper::Mbc5,         true,  true,  false, true),
+            0x20 => (Mapper::Mbc6,         true,  true,  false, false),
+            0x22 => (Mapper::Mbc7,         true,  true,  false, true),
+            0xFC => (Mapper::PocketCamera, true,  true,  false, false),
+            0xFD => (Mapper::Tama5,        true,  true,  true,  false),
+            0xFE => (Mapper::Huc3,         true,  true,  true,  false),
+            0xFF => (Mapper::Huc1,         true,  true,  false, false),
+            _    => (Mapper::Unknown(code), false, false, false, false),
+        };
+        Self { code, mapper, ram, battery, timer, rumble }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CgbFlag {
+    /// Monochrome only cart
+    None,
+    /// Works on DMG but uses CGB features when available (0x80)
+    Enhanced,
+    /// Only runs on a CGB (0xC0)
+    Only,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+    Unknown(u8),
+}
+
+/// Everything decoded from 0x0100-0x014F, plus the checks that can be done against the rest of the ROM
+#[derive(Clone, Debug)]
+pub struct CartHeader {
+    pub title: String,
+    pub manufacturer: String,
+    pub cgb: CgbFlag,
+    pub sgb: bool,
+    pub cart_type: CartType,
+    /// ROM size in bytes, None for an unknown size code
+    pub rom_size: Option<usize>,
+    pub rom_size_code: u8,
+    /// External RAM size in bytes, None for an unknown size code
+    pub ram_size: Option<usize>,
+    pub ram_size_code: u8,
+    pub destination: Destination,
+    pub old_licensee: u8,
+    pub new_licensee: String,
+    pub publisher: &'static str,
+    pub version: u8,
+    pub logo_valid: bool,
+    pub header_checksum: u8,
+    pub header_checksum_computed: u8,
+    pub global_checksum: u16,
+    pub global_checksum_computed: u16,
+}
+
+impl CartHeader {
+    /// Decode the header of a ROM image, `rom` must reach at least to 0x0150
+    pub fn parse(rom: &[u8]) -> Self {
+        let cgb: CgbFlag = match rom[0x0143] {
+            0x80 => CgbFlag::Enhanced,
+            0xC0 => CgbFlag::Only,
+            _ => CgbFlag::None,
+        };
+        // CGB carts took the last bytes of the title for the manufacturer code and CGB flag
+        let title_end: usize = if cgb == CgbFlag::None {0x0144} else {0x013F};
+        let manufacturer: String = if cgb == CgbFlag::None {String::new()} else {ascii(&rom[0x013F..0x0143])};
+
+        let new_licensee: String = ascii(&rom[0x0144..0x0146]);
+        let old_licensee: u8 = rom[0x014B];
+        let publisher: &'static str = if old_licensee == 0x33 {
+            new_licensee_name(&new_licensee)
+        }
+        else {
+            old_licensee_name(old_licensee)
+        };
+
+        let mut header_checksum_computed: u8 = 0;
+        for byte in &rom[0x0134..0x014D] {
+            header_checksum_computed = header_checksum_computed.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        let mut global_checksum_computed: u16 = 0;
+        for (i, byte) in rom.iter().enumerate() {
+            if i != 0x014E && i != 0x014F {
+                global_checksum_computed = global_checksum_computed.wrapping_add(*byte as u16);
+            }
+        }
+
+        Self {
+            title: ascii(&rom[0x0134..title_end]),
+            manufacturer,
+            cgb,
+            sgb: rom[0x0146] == 0x03,
+            cart_type: CartType::from_code(rom[0x0147]),
+            rom_size: rom_size_bytes(rom[0x0148]),
+            rom_size_code: rom[0x0148],
+            ram_size: ram_size_bytes(rom[0x0149]),
+            ram_size_code: rom[0x0149],
+            destination: match rom[0x014A] {
+                0x00 => Destination::Japan,
+                0x01 => Destination::Overseas,
+                code => Destination::Unknown(code),
+            },
+            old_licensee,
+            new_licensee,
+            publisher,
+            version: rom[0x014C],
+            logo_valid: rom[0x0104..0x0134] == NINTENDO_LOGO,
+            header_checksum: rom[0x014D],
+            header_checksum_computed,
+            global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16,
+            global_checksum_computed,
+        }
+    }
+
+    /// The boot ROM refuses to start a cart whose header checksum doesn't match
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.header_checksum_computed
+    }
+
+    /// Not checked by hardware, but a mismatch usually means a bad dump or a patched ROM
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.global_checksum_computed
+    }
+}
+
+impl fmt::Display for CartHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pass = |valid: bool| if valid {"Passed"} else {"Failed"};
+        writeln!(f, "Cartridge Loaded")?;
+        writeln!(f, "\tTitle: {}", self.title)?;
+        writeln!(f, "\tType: {:#04X} {:?}", self.cart_type.code, self.cart_type.mapper)?;
+        match self.rom_size {
+            Some(size) => writeln!(f, "\tRom Size: {} KiB", size / 1024)?,
+            None => writeln!(f, "\tRom Size: unknown ({:#04X})", self.rom_size_code)?,
+        }
+        match self.ram_size {
+            Some(size) => writeln!(f, "\tRam Size: {} KiB", size / 1024)?,
+            None => writeln!(f, "\tRam Size: unknown ({:#04X})", self.ram_size_code)?,
+        }
+        writeln!(f, "\tCGB: {:?} SGB: {}", self.cgb, self.sgb)?;
+        writeln!(f, "\tDestination: {:?}", self.destination)?;
+        // 0x33 means the two character new licensee code is used instead
+        if self.old_licensee == 0x33 {
+            writeln!(f, "\tPublisher: {} ({})", self.publisher, self.new_licensee)?;
+        }
+        else {
+            writeln!(f, "\tPublisher: {} ({:#04X})", self.publisher, self.old_licensee)?;
+        }
+        if !self.manufacturer.is_empty() {
+            writeln!(f, "\tManufacturer: {}", self.manufacturer)?;
+        }
+        writeln!(f, "\tVersion: {}", self.version)?;
+        writeln!(f, "Logo: {}", pass(self.logo_valid))?;
+        writeln!(f, "Header Checksum: {}", pass(self.header_checksum_valid()))?;
+        write!(f, "Global Checksum: {}", pass(self.global_checksum_valid()))
+    }
+}
+
+/// Header strings are upper case ASCII padded with zeros, anything else is dropped
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|byte| **byte != 0)
+        .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+        .map(|byte| *byte as char)
+        .collect()
+}
+
+/// ROM size in bytes from the header code at 0x0148
+fn rom_size_bytes(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some(0x8000 << code),
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+        _ => None,
+    }
+}
+
+/// External RAM size in bytes from the header code at 0x0149
+fn ram_size_bytes(code: u8) -> Option<usize> {
+    match code {
+        0x00 => Some(0),
+        0x01 => Some(0x800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x2000 * 4),
+        0x04 => Some(0x2000 * 16),
+        0x05 => Some(0x2000 * 8),
+        _ => None,
+    }
+}
+
+fn new_licensee_name(code: &str) -> &'static str {
+    match code {
+        "00" => "None",
+        "01" => "Nintendo R&D1",
+        "08" => "Capcom",
+        "13" => "Electronic Arts",
+        "18" => "Hudson Soft",
+        "19" => "b-ai",
+        "20" => "kss",
+        "22" => "pow",
+        "24" => "PCM Complete",
+        "25" => "san-x",
+        "28" => "Kemco Japan",
+        "29" => "seta",
+        "30" => "Viacom",
+        "31" => "Nintendo",
+        "32" => "Bandai",
+        "33" => "Ocean/Acclaim",
+        "34" => "Konami",
+        "35" => "Hector",
+        "37" => "Taito",
+        "38" => "Hudson",
+        "39" => "Banpresto",
+        "41" => "Ubi Soft",
+        "42" => "Atlus",
+        "44" => "Malibu",
+        "46" => "angel",
+        "47" => "Bullet-Proof",
+        "49" => "irem",
+        "50" => "Absolute",
+        "51" => "Acclaim",
+        "52" => "Activision",
+        "53" => "American sammy",
+        "54" => "Konami",
+        "55" => "Hi tech entertainment",
+        "56" => "LJN",
+        "57" => "Matchbox",
+        "58" => "Mattel",
+        "59" => "Milton Bradley",
+        "60" => "Titus",
+        "61" => "Virgin",
+        "64" => "LucasArts",
+        "67" => "Ocean",
+        "69" => "Electronic Arts",
+        "70" => "Infogrames",
+        "71" => "Interplay",
+        "72" => "Broderbund",
+        "73" => "sculptured",
+        "75" => "sci",
+        "78" => "THQ",
+        "79" => "Accolade",
+        "80" => "misawa",
+        "83" => "lozc",
+        "86" => "Tokuma Shoten Intermedia",
+        "87" => "Tsukuda Original",
+        "91" => "Chunsoft",
+        "92" => "Video system",
+        "93" => "Ocean/Acclaim",
+        "95" => "Varie",
+        "96" => "Yonezawa/s'pal",
+        "97" => "Kaneko",
+        "99" => "Pack in soft",
+        "9H" => "Bottom Up",
+        "A4" => "Konami (Yu-Gi-Oh!)",
+        _ => "Unknown",
+    }
+}
+
+fn old_licensee_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "None",
+        0x01 => "Nintendo",
+        0x08 => "Capcom",
+        0x09 => "Hot-B",
+        0x0A => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C => "Elite Systems",
+        0x13 => "Electronic Arts",
+        0x18 => "Hudsonsoft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F => "Virgin Interactive",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 => "Kotobuki Systems",
+        0x29 => "Seta",
+        0x30 => "Infogrames",
+        0x31 => "Nintendo",
+        0x32 => "Bandai",
+        0x34 => "Konami",
+        0x35 => "HectorSoft",
+        0x38 => "Capcom",
+        0x39 => "Banpresto",
+        0x3C => "Entertainment i",
+        0x3E => "Gremlin",
+        0x41 => "Ubisoft",
+        0x42 => "Atlus",
+        0x44 => "Malibu",
+        0x46 => "Angel",
+        0x47 => "Spectrum Holoby",
+        0x49 => "Irem",
+        0x4A => "Virgin Interactive",
+        0x4D => "Malibu",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 => "Acclaim",
+        0x52 => "Activision",
+        0x53 => "American Sammy",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus",
+        0x61 => "Virgin Interactive",
+        0x67 => "Ocean Interactive",
+        0x69 => "Electronic Arts",
+        0x6E => "Elite Systems",
+        0x6F => "Electro Brain",
+        0x70 => "Infogrames",
+        0x71 => "Interplay",
+        0x72 => "Broderbund",
+        0x73 => "Sculptered Soft",
+        0x75 => "The Sales Curve",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "Microprose",
+        0x7F => "Kemco",
+        0x80 => "Misawa Entertainment",
+        0x83 => "Lozc",
+        0x86 => "Tokuma Shoten Intermedia",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai",
+        0x8E => "Ape",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft",
+        0x92 => "Video System",
+        0x93 => "Tsubaraya Productions",
+        0x95 => "Varie",
+        0x96 => "Yonezawa/S'Pal",
+        0x97 => "Kaneko",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9D => "Banpresto",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA2 => "Bandai",
+        0xA4 => "Konami",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAA => "Broderbund",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB0 => "Acclaim",
+        0xB1 => "ASCII or Nexsoft",
+        0xB2 => "Bandai",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy",
+        0xC0 => "Taito",
+        0xC2 => "Kemco",
+        0xC3 => "Squaresoft",
+        0xC4 => "Tokuma Shoten Intermedia",
+        0xC5 => "Data East",
+        0xC6 => "Tonkinhouse",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra",
+        0xCB => "Vap",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xCE => "Pony Canyon",
+        0xCF => "Angel",
+        0xD0 => "Taito",
+        0xD1 => "Sofel",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha",
+        0xD6 => "Naxat Soft",
+        0xD7 => "Copya System",
+        0xD9 => "Banpresto",
+        0xDA => "Tomy",
+        0xDB => "LJN",
+        0xDD => "NCS",
+        0xDE => "Human",
+        0xDF => "Altron",
+        0xE0 => "Jaleco",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE3 => "Varie",
+        0xE5 => "Epcoh",
+        0xE7 => "Athena",
+        0xE8 => "Asmik ACE Entertainment",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEB => "Atlus",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        0xFF => "LJN",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32 KiB ROM with a valid logo, title and both checksums filled in
+    fn test_rom(title: &[u8], cart_type: u8) -> Vec<u8> {
+        let mut rom: Vec<u8> = vec![0; 0x8000];
+        rom[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x0147] = cart_type;
+        rom[0x0200] = 0x42;
+        fix_checksums(&mut rom);
+        return rom;
+    }
+
+    fn fix_checksums(rom: &mut [u8]) {
+        let mut header: u8 = 0;
+        for byte in &rom[0x0134..0x014D] {
+            header = header.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        rom[0x014D] = header;
+        let mut global: u16 = 0;
+        for (i, byte) in rom.iter().enumerate() {
+            if i != 0x014E && i != 0x014F {
+                global = global.wrapping_add(*byte as u16);
+            }
+        }
+        rom[0x014E..0x0150].copy_from_slice(&global.to_be_bytes());
+    }
+
+    #[test]
+    fn parses_a_dmg_header() {
+        let mut rom: Vec<u8> = test_rom(b"TETRIS", 0x03);
+        rom[0x0146] = 0x03;
+        rom[0x0148] = 0x00;
+        rom[0x0149] = 0x03;
+        rom[0x014A] = 0x01;
+        rom[0x014B] = 0x01;
+        rom[0x014C] = 0x02;
+        fix_checksums(&mut rom);
+        let header: CartHeader = CartHeader::parse(&rom);
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer, "");
+        assert_eq!(header.cgb, CgbFlag::None);
+        assert!(header.sgb);
+        assert_eq!(header.cart_type, CartType { code: 0x03, mapper: Mapper::Mbc1, ram: true, battery: true, timer: false, rumble: false });
+        assert_eq!(header.rom_size, Some(0x8000));
+        assert_eq!(header.ram_size, Some(0x8000));
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.publisher, "Nintendo");
+        assert_eq!(header.version, 2);
+        assert!(header.logo_valid);
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+    }
+
+    #[test]
+    fn parses_a_cgb_header() {
+        let mut rom: Vec<u8> = test_rom(b"POKEMON_SLVAAXE\xC0", 0x10);
+        rom[0x0144..0x0146].copy_from_slice(b"01");
+        rom[0x014B] = 0x33;
+        rom[0x0148] = 0x06;
+        rom[0x0149] = 0x09;
+        let header: CartHeader = CartHeader::parse(&rom);
+        // The last 5 title bytes are the manufacturer code and the CGB flag
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer, "AAXE");
+        assert_eq!(header.cgb, CgbFlag::Only);
+        assert_eq!(header.publisher, "Nintendo R&D1");
+        assert_eq!(header.new_licensee, "01");
+        assert_eq!(header.rom_size, Some(0x200000));
+        assert_eq!(header.ram_size, None);
+        assert_eq!(header.cart_type.mapper, Mapper::Mbc3);
+        assert!(header.cart_type.timer);
+    }
+
+    #[test]
+    fn cart_type_codes() {
+        assert_eq!(CartType::from_code(0x00).mapper, Mapper::RomOnly);
+        assert_eq!(CartType::from_code(0x06), CartType { code: 0x06, mapper: Mapper::Mbc2, ram: false, battery: true, timer: false, rumble: false });
+        assert_eq!(CartType::from_code(0x0F), CartType { code: 0x0F, mapper: Mapper::Mbc3, ram: false, battery: true, timer: true, rumble: false });
+        assert_eq!(CartType::from_code(0x1E), CartType { code: 0x1E, mapper: Mapper::Mbc5, ram: true, battery: true, timer: false, rumble: true });
+        assert_eq!(CartType::from_code(0xFE).mapper, Mapper::Huc3);
+        assert_eq!(CartType::from_code(0xFF).mapper, Mapper::Huc1);
+        assert_eq!(CartType::from_code(0x04), CartType { code: 0x04, mapper: Mapper::Unknown(0x04), ram: false, battery: false, timer: false, rumble: false });
+    }
+
+    #[test]
+    fn checksum_mismatches() {
+        let mut rom: Vec<u8> = test_rom(b"TEST", 0x00);
+        // Outside the header only the global checksum changes
+        rom[0x0200] ^= 0xFF;
+        let header: CartHeader = CartHeader::parse(&rom);
+        assert!(header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+        rom[0x014C] ^= 0xFF;
+        let header: CartHeader = CartHeader::parse(&rom);
+        assert!(!header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+        // The stored global checksum itself isn't part of the sum
+        let mut rom: Vec<u8> = test_rom(b"TEST", 0x00);
+        rom[0x014E] ^= 0xFF;
+        assert!(!CartHeader::parse(&rom).global_checksum_valid());
+        rom[0x014E] ^= 0xFF;
+        assert!(CartHeader::parse(&rom).global_checksum_valid());
+    }
+
+    #[test]
+    fn validation_report() {
+        let mut rom: Vec<u8> = test_rom(b"TEST", 0x13);
+        rom[0x0104] = 0x00;
+        rom[0x0148] = 0x01;
+        rom[0x014B] = 0x01;
+        let report: String = CartHeader::parse(&rom).to_string();
+        let expected: [&str; 12] = [
+            "Cartridge Loaded",
+            "\tTitle: TEST",
+            "\tType: 0x13 Mbc3",
+            "\tRom Size: 64 KiB",
+            "\tRam Size: 0 KiB",
+            "\tCGB: None SGB: false",
+            "\tDestination: Japan",
+            "\tPublisher: Nintendo (0x01)",
+            "\tVersion: 0",
+            "Logo: Failed",
+            "Header Checksum: Failed",
+            "Global Checksum: Failed",
+        ];
+        assert_eq!(report.split('\n').collect::<Vec<&str>>(), expected);
+    }
+}
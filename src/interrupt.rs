@@ -0,0 +1,152 @@
+////////////////
+/// 
+/// interrupt.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/Interrupts.html - IE, IF and dispatch
+/// 
+/// 0xFF0F : IF - requested interrupts, upper 3 bits read as 1
+/// 0xFFFF : IE - enabled interrupts
+/// 
+/// Bit  Interrupt  Vector  Priority
+///  0   VBlank     0x40    highest
+///  1   LCD STAT   0x48
+///  2   Timer      0x50
+///  3   Serial     0x58
+///  4   Joypad     0x60    lowest
+/// 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+const PRIORITY: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LcdStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn bit(self) -> u8 {
+        match self {
+            Interrupt::VBlank => 1 << 0,
+            Interrupt::LcdStat => 1 << 1,
+            Interrupt::Timer => 1 << 2,
+            Interrupt::Serial => 1 << 3,
+            Interrupt::Joypad => 1 << 4,
+        }
+    }
+
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LcdStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+}
+
+pub struct Interrupts {
+    ie: u8,
+    ifr: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        // VBlank is already requested when the boot ROM hands over
+        Self { ie: 0x00, ifr: 0x01 }
+    }
+
+    pub fn request(&mut self, it: Interrupt) {
+        self.ifr |= it.bit();
+    }
+
+    /// Clear only the request that is being serviced
+    pub fn acknowledge(&mut self, it: Interrupt) {
+        self.ifr &= !it.bit();
+    }
+
+    /// Requested and enabled interrupts, regardless of IME
+    pub fn pending(&self) -> u8 {
+        self.ie & self.ifr & 0x1F
+    }
+
+    /// The pending interrupt that would be serviced next
+    pub fn highest_pending(&self) -> Option<Interrupt> {
+        let pending: u8 = self.pending();
+        PRIORITY.iter().copied().find(|it| pending & it.bit() != 0)
+    }
+
+    pub fn read_if(&self) -> u8 {
+        self.ifr | 0xE0
+    }
+
+    pub fn write_if(&mut self, data: u8) {
+        self.ifr = data & 0x1F;
+    }
+
+    pub fn read_ie(&self) -> u8 {
+        self.ie
+    }
+
+    pub fn write_ie(&mut self, data: u8) {
+        self.ie = data;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highest_priority_pending_wins() {
+        let mut interrupts: Interrupts = Interrupts::new();
+        interrupts.write_if(0x00);
+        assert_eq!(interrupts.highest_pending(), None);
+        interrupts.request(Interrupt::Joypad);
+        interrupts.request(Interrupt::Timer);
+        // Requested but not enabled
+        assert_eq!(interrupts.highest_pending(), None);
+        interrupts.write_ie(0x1F);
+        assert_eq!(interrupts.highest_pending(), Some(Interrupt::Timer));
+        interrupts.request(Interrupt::VBlank);
+        assert_eq!(interrupts.highest_pending(), Some(Interrupt::VBlank));
+        interrupts.write_ie(0x12);
+        assert_eq!(interrupts.highest_pending(), Some(Interrupt::Joypad));
+    }
+
+    #[test]
+    fn acknowledge_clears_only_the_serviced_request() {
+        let mut interrupts: Interrupts = Interrupts::new();
+        interrupts.write_ie(0x1F);
+        interrupts.write_if(0x1F);
+        interrupts.acknowledge(Interrupt::LcdStat);
+        assert_eq!(interrupts.read_if(), 0xE0 | 0x1D);
+        assert_eq!(interrupts.highest_pending(), Some(Interrupt::VBlank));
+        interrupts.acknowledge(Interrupt::VBlank);
+        assert_eq!(interrupts.highest_pending(), Some(Interrupt::Timer));
+    }
+
+    #[test]
+    fn if_upper_bits_read_as_one() {
+        let mut interrupts: Interrupts = Interrupts::new();
+        assert_eq!(interrupts.read_if(), 0xE1);
+        interrupts.write_if(0xFF);
+        assert_eq!(interrupts.read_if(), 0xFF);
+        interrupts.write_if(0x00);
+        assert_eq!(interrupts.read_if(), 0xE0);
+        // IE keeps all 8 bits, the upper 3 just don't enable anything
+        interrupts.write_ie(0xE0);
+        assert_eq!(interrupts.read_ie(), 0xE0);
+        interrupts.write_if(0x1F);
+        assert_eq!(interrupts.pending(), 0x00);
+    }
+}
@@ -1,42 +1,151 @@
+use std::io;
+use std::path::Path;
+use crate::apu::Apu;
+use crate::interrupt::{Interrupt, Interrupts};
+use crate::joypad::{Button, Joypad};
+use crate::timer::Timer;
+use crate::wav::Recorder;
+
+/// Machine cycles to shift one bit out over serial with the internal 8192 Hz clock
+const SERIAL_BIT_CYCLES: u16 = 128;
+
+/*
+ * serialData: SB and SC
+ * serial_cycles: machine cycles into the current serial transfer
+ * timer: DIV, TIMA, TMA and TAC
+ * joypad: P1/JOYP
+ * apu: sound registers and wave RAM
+ */
 pub struct IO{
     serialData: [char; 2],
-
+    serial_cycles: u16,
+    timer: Timer,
+    joypad: Joypad,
+    apu: Apu,
 }
 impl IO{
-    pub fn new()-> Self{
+    pub fn new(sample_rate: u32)-> Self{
         Self{
-            serialData: [' ', ' ']
+            serialData: [' ', ' '],
+            serial_cycles: 0,
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(sample_rate),
         }
     }
-    pub fn write(&mut self, addr: u16, val: u8){
+
+    /// Writing DIV or executing STOP clears the whole system counter
+    pub fn reset_div(&mut self){
+        let old: u16 = self.timer.counter();
+        self.timer.reset_div();
+        self.frame_sequencer(old);
+    }
+
+    /// The APU frame sequencer steps when DIV bit 4 (system counter bit 12) falls
+    fn frame_sequencer(&mut self, old: u16){
+        if old & 0x1000 != 0 && self.timer.counter() & 0x1000 == 0 {
+            self.apu.frame_step();
+        }
+    }
+
+    /// Interleaved stereo samples the APU produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32>{
+        return self.apu.take_samples();
+    }
+
+    /// Stream APU output to a stereo WAV file at `path`, and one per channel when `separate` is set
+    pub fn start_recording(&mut self, path: &Path, separate: bool) -> io::Result<()>{
+        let recorder: Recorder = Recorder::create(path, self.apu.sample_rate(), separate)?;
+        return self.apu.start_recording(recorder);
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()>{
+        return self.apu.stop_recording();
+    }
+
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool){
+        self.apu.set_muted(channel, muted);
+    }
+
+    pub fn set_channel_solo(&mut self, channel: usize, soloed: bool){
+        self.apu.set_solo(channel, soloed);
+    }
+
+    pub fn channel_scope(&self, channel: usize) -> Vec<f32>{
+        return self.apu.scope(channel);
+    }
+
+    /// A selected button line is low, which is what wakes the CPU from STOP
+    pub fn joypad_low(&self) -> bool{
+        return self.joypad.lines() != 0x0F;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool, interrupts: &mut Interrupts){
+        self.joypad.set_button(button, pressed, interrupts);
+    }
+
+    /// Advance the timer, the APU and the serial port by one machine cycle
+    pub fn tick(&mut self, interrupts: &mut Interrupts){
+        let old: u16 = self.timer.counter();
+        self.timer.tick(interrupts);
+        self.frame_sequencer(old);
+        self.apu.tick();
+        let sc: u8 = self.serialData[1] as u8;
+        // Only transfers on the internal clock complete, there is never a link partner
+        if sc & 0x81 != 0x81 {
+            return;
+        }
+        self.serial_cycles += 1;
+        if self.serial_cycles == SERIAL_BIT_CYCLES * 8 {
+            self.serial_cycles = 0;
+            // Nothing connected, so only 1s are shifted in
+            self.serialData[0] = 0xFF as char;
+            self.serialData[1] = (sc & 0x7F) as char;
+            interrupts.request(Interrupt::Serial);
+        }
+    }
+    pub fn write(&mut self, addr: u16, val: u8, interrupts: &mut Interrupts){
         //println!("addr: {:#04x}, val: {:#02x}", addr, val);
-        if addr == 0xFF01{
+        if addr == 0xFF00{
+            self.joypad.write(val, interrupts);
+        }
+        else if addr == 0xFF01{
             println!(" sd0 {}", val);
             self.serialData[0] = val as char;
         }
         else if addr == 0xFF02{
             self.serialData[1] = val as char;
+            self.serial_cycles = 0;
             println!(" sd1 {}", val);
         }
-        else if addr <= 0xFF07 && addr >= 0xFF07{
-            //timer
+        else if addr == 0xFF04{
+            self.reset_div();
         }
-        else if addr == 0xFF0F{
-            //return cpu flags
+        else if addr >= 0xFF05 && addr <= 0xFF07{
+            self.timer.write(addr, val);
+        }
+        else if addr >= 0xFF10 && addr <= 0xFF3F{
+            self.apu.write(addr, val);
         }
 
     }
 
     pub fn read(&mut self, addr: u16) -> u8{
-        if addr == 0xFF01{
+        if addr == 0xFF00{
+            return self.joypad.read()
+        }
+        else if addr == 0xFF01{
             return self.serialData[0] as u8
         }
         else if addr == 0xFF02{
             return self.serialData[1] as u8
         }
-        else if addr <= 0xFF07 && addr >= 0xFF07{
-            //timer
-        } 
+        else if addr >= 0xFF04 && addr <= 0xFF07{
+            return self.timer.read(addr)
+        }
+        else if addr >= 0xFF10 && addr <= 0xFF3F{
+            return self.apu.read(addr)
+        }
         
         //println!("{}, {}",self.serialData[0], self.serialData[1]);
         return 0;
@@ -0,0 +1,126 @@
+////////////////
+/// 
+/// joypad.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/Joypad_Input.html - P1/JOYP
+/// 
+/// 0xFF00 : P1/JOYP
+///     bit 5 - select action buttons (0 = selected)
+///     bit 4 - select direction buttons (0 = selected)
+///     bit 3 - Down  / Start   (0 = pressed)
+///     bit 2 - Up    / Select
+///     bit 1 - Left  / B
+///     bit 0 - Right / A
+/// 
+/// The lower nibble is wired AND-style: a line reads 0 if a pressed button of any
+/// selected group pulls it low. A line going from high to low requests the joypad interrupt.
+/// 
+use crate::interrupt::{Interrupt, Interrupts};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+pub const BUTTONS: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+];
+
+impl Button {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None,
+        }
+    }
+
+    /// Bit in `Joypad::pressed`, directions in the lower nibble and actions in the upper
+    fn bit(self) -> u8 {
+        match self {
+            Button::Right => 1 << 0,
+            Button::Left => 1 << 1,
+            Button::Up => 1 << 2,
+            Button::Down => 1 << 3,
+            Button::A => 1 << 4,
+            Button::B => 1 << 5,
+            Button::Select => 1 << 6,
+            Button::Start => 1 << 7,
+        }
+    }
+}
+
+/*
+ * select: P1 bits 4-5 as last written
+ * pressed: buttons held down, see Button::bit
+ */
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self { select: 0x30, pressed: 0 }
+    }
+
+    /// The lower nibble of P1, 0 where a selected button is pressed
+    pub fn lines(&self) -> u8 {
+        let mut low: u8 = 0;
+        if self.select & 0x10 == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            low |= self.pressed >> 4;
+        }
+        return !low & 0x0F;
+    }
+
+    pub fn read(&self) -> u8 {
+        return 0xC0 | self.select | self.lines();
+    }
+
+    pub fn write(&mut self, data: u8, interrupts: &mut Interrupts) {
+        let old: u8 = self.lines();
+        self.select = data & 0x30;
+        self.check_interrupt(old, interrupts);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool, interrupts: &mut Interrupts) {
+        let old: u8 = self.lines();
+        if pressed {
+            self.pressed |= button.bit();
+        }
+        else {
+            self.pressed &= !button.bit();
+        }
+        self.check_interrupt(old, interrupts);
+    }
+
+    fn check_interrupt(&self, old: u8, interrupts: &mut Interrupts) {
+        if old & !self.lines() != 0 {
+            interrupts.request(Interrupt::Joypad);
+        }
+    }
+}
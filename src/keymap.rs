@@ -0,0 +1,120 @@
+////////////////
+/// 
+/// keymap.rs
+/// 
+/// Which keyboard keys press which joypad buttons, loaded from a text file:
+///     # comment
+///     <button> = <key>
+/// Buttons are right, left, up, down, a, b, select and start. Keys use the minifb
+/// names (Z, Enter, Up, LeftShift, NumPad8, ...), case doesn't matter. A button can
+/// be bound to several keys by repeating it.
+/// 
+/// Without a key map file the defaults are the arrow keys, Z = A, X = B,
+/// Enter = Start and Backspace = Select. Buttons a file doesn't mention keep
+/// their default keys.
+/// 
+use minifb::Key;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::joypad::Button;
+
+/// Every key minifb reports, used to look keys up by name
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus,
+    Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu,
+    Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6,
+    Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+fn key_from_name(name: &str) -> Option<Key> {
+    KEYS.iter().copied().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+pub struct KeyMap {
+    bindings: Vec<(Key, Button)>,
+}
+
+impl KeyMap {
+    pub fn new() -> Self {
+        Self {
+            bindings: vec![
+                (Key::Right, Button::Right),
+                (Key::Left, Button::Left),
+                (Key::Up, Button::Up),
+                (Key::Down, Button::Down),
+                (Key::Z, Button::A),
+                (Key::X, Button::B),
+                (Key::Backspace, Button::Select),
+                (Key::Enter, Button::Start),
+            ],
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text: String = fs::read_to_string(path)?;
+        let mut bindings: Vec<(Key, Button)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line: &str = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, reason));
+            let (button, key): (&str, &str) = match line.split_once('=') {
+                Some((button, key)) => (button.trim(), key.trim()),
+                None => return Err(invalid(String::from("expected <button> = <key>"))),
+            };
+            let button: Button = match Button::from_name(button) {
+                Some(button) => button,
+                None => return Err(invalid(format!("unknown button '{}'", button))),
+            };
+            let key: Key = match key_from_name(key) {
+                Some(key) => key,
+                None => return Err(invalid(format!("unknown key '{}'", key))),
+            };
+            bindings.push((key, button));
+        }
+        // Buttons listed in the file drop their default keys, the rest keep them
+        let mut keymap: Self = Self::new();
+        keymap.bindings.retain(|(_, button)| !bindings.iter().any(|(_, b)| b == button));
+        keymap.bindings.extend(bindings);
+        return Ok(keymap);
+    }
+
+    /// Whether any key bound to `button` is in `keys`
+    pub fn pressed(&self, button: Button, keys: &[Key]) -> bool {
+        self.bindings.iter().any(|(key, b)| *b == button && keys.contains(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_file_keeps_default_keys() {
+        let path: std::path::PathBuf = std::env::temp_dir().join("gb_at2_partial_keys.cfg");
+        fs::write(&path, "# swap A and B\na = X\nb = Z\nb = C\n").unwrap();
+        let keymap: KeyMap = KeyMap::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(keymap.pressed(Button::A, &[Key::X]));
+        assert!(!keymap.pressed(Button::A, &[Key::Z]));
+        assert!(keymap.pressed(Button::B, &[Key::Z]));
+        assert!(keymap.pressed(Button::B, &[Key::C]));
+        assert!(!keymap.pressed(Button::B, &[Key::X]));
+        // Not in the file
+        assert!(keymap.pressed(Button::Start, &[Key::Enter]));
+        assert!(keymap.pressed(Button::Up, &[Key::Up]));
+    }
+}
@@ -0,0 +1,27 @@
+////////////////
+/// 
+/// lib.rs
+/// 
+/// The emulator as a library. Everything from the cartridge to the WAV recorder is
+/// reachable from here, so tools and tests can build a CPU and run it without a window
+/// or an audio device. main.rs is the frontend built on top of it.
+/// 
+pub mod bus;
+pub mod cpu;
+pub mod cart;
+pub mod util;
+pub mod log;
+pub mod io;
+pub mod ppu;
+pub mod screen;
+pub mod event;
+pub mod header;
+pub mod patch;
+pub mod interrupt;
+pub mod timer;
+pub mod joypad;
+pub mod keymap;
+pub mod apu;
+pub mod audio;
+pub mod wav;
+pub mod scope;
@@ -1,41 +1,267 @@
-mod bus;
-mod cpu;
-mod cart;
-mod util;
-mod log;
-mod io;
-mod ppu;
-mod screen;
-
-
-use cpu::CPU;
-use cart::Cart;
-use ppu::GPU;
-use bus::Bus;
-use io::IO;
-use screen::Screen;
+use gb_at2::cpu::CPU;
+use gb_at2::cart::Cart;
+use gb_at2::ppu::{GPU, Renderer};
+use gb_at2::bus::{Bus, Model};
+use gb_at2::io::IO;
+use gb_at2::screen::Screen;
+use gb_at2::keymap::KeyMap;
+use gb_at2::event::Event;
+use gb_at2::audio::Audio;
+use gb_at2::scope::ScopeWindow;
+use gb_at2::apu::CHANNELS;
 use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Instructions between flushes of battery-backed cartridge RAM, a few seconds of play
+const SAVE_INTERVAL: u64 = 1 << 22;
+/// Key map picked up from the working directory when --keys isn't given
+const DEFAULT_KEYMAP: &str = "keys.cfg";
+/// Sample rate the APU runs at when there is no audio device
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     //Load rom file in
     let args:Vec<String> = env::args().collect();
-    let rom_path = format!("../roms/{}.gb", &args[1]);
+    let usage: String = format!("Usage: {} [--model dmg|cgb-c|cgb-d|cgb-e] [--renderer scanline|fifo] [--keys keys.cfg] [--record-audio out.wav [--record-channels]] [--scope] [--headless] [--frames N] <rom.gb|rom.gbc|rom.zip|rom.gz>", &args[0]);
+    let mut rom_arg: Option<String> = None;
+    let mut model: Model = Model::Dmg;
+    let mut renderer: Renderer = Renderer::Scanline;
+    let mut keys_path: Option<PathBuf> = None;
+    let mut record_path: Option<PathBuf> = None;
+    let mut record_channels: bool = false;
+    let mut show_scope: bool = false;
+    let mut headless: bool = false;
+    let mut frames: Option<u64> = None;
+    let mut i: usize = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--model" => {
+                i += 1;
+                model = match args.get(i).and_then(|name| Model::from_name(name)) {
+                    Some(model) => model,
+                    None => fail(&usage),
+                };
+            },
+            "--renderer" => {
+                i += 1;
+                renderer = match args.get(i).and_then(|name| Renderer::from_name(name)) {
+                    Some(renderer) => renderer,
+                    None => fail(&usage),
+                };
+            },
+            "--keys" => {
+                i += 1;
+                match args.get(i) {
+                    Some(path) => keys_path = Some(PathBuf::from(path)),
+                    None => fail(&usage),
+                }
+            },
+            "--record-audio" => {
+                i += 1;
+                match args.get(i) {
+                    Some(path) => record_path = Some(PathBuf::from(path)),
+                    None => fail(&usage),
+                }
+            },
+            "--record-channels" => record_channels = true,
+            "--scope" => show_scope = true,
+            "--headless" => headless = true,
+            "--frames" => {
+                i += 1;
+                frames = match args.get(i).and_then(|count| count.parse::<u64>().ok()) {
+                    Some(count) => Some(count),
+                    None => fail(&usage),
+                };
+            },
+            arg => rom_arg = Some(arg.to_string()),
+        }
+        i += 1;
+    }
+    let rom_arg: String = match rom_arg {
+        Some(arg) => arg,
+        None => fail(&usage),
+    };
+    // Nothing closes a headless run, so it needs a frame count to stop at
+    if headless && frames.is_none() {
+        fail(&usage);
+    }
+    // Plain names still resolve to the bundled roms directory
+    let mut rom_path: PathBuf = PathBuf::from(&rom_arg);
+    if !rom_path.exists() {
+        rom_path = PathBuf::from(format!("../roms/{}.gb", &rom_arg));
+    }
     let mut rom: Cart = Cart::new();
-    rom.load_rom(rom_path);
-    let mut io: IO = IO::new();
-    let mut gpu: GPU = GPU::new();
-    let mut screen: Screen = Screen::new();
+    match rom.load_rom(rom_path) {
+        Ok(Some(patch)) => println!("Applied patch {}", patch.display()),
+        Ok(None) => {},
+        Err(err) => fail(&format!("Failed to load ROM: {}", err)),
+    }
+    let mut title: String = String::from("gb_at2");
+    if let Some(header) = rom.header() {
+        println!("{}", header);
+        if !header.title.is_empty() {
+            title = format!("{} - {}", header.title, title);
+        }
+    }
+    // Headless runs skip the audio device entirely, the APU still runs for recording
+    let audio: Option<Audio> = if headless {None} else {Audio::new()};
+    if audio.is_none() && !headless {
+        // Without an audio device emulation carries on silently
+        eprintln!("No audio output, running without sound");
+    }
+    let sample_rate: u32 = audio.as_ref().map_or(DEFAULT_SAMPLE_RATE, |audio| audio.sample_rate());
+    let io: IO = IO::new(sample_rate);
+    let gpu: GPU = GPU::new(renderer);
+    // An explicitly given key map has to load, the default one is optional
+    let keymap: KeyMap = match keys_path {
+        Some(path) => match KeyMap::load(&path) {
+            Ok(keymap) => keymap,
+            Err(err) => fail(&format!("Failed to load key map {}: {}", path.display(), err)),
+        },
+        None => match KeyMap::load(DEFAULT_KEYMAP) {
+            Ok(keymap) => keymap,
+            Err(err) if err.kind() == ErrorKind::NotFound => KeyMap::new(),
+            Err(err) => {
+                eprintln!("Failed to load key map {}, using the default keys: {}", DEFAULT_KEYMAP, err);
+                KeyMap::new()
+            },
+        },
+    };
+    let screen: Option<Screen> = if headless {
+        None
+    } else {
+        match Screen::new(&title, keymap) {
+            Ok(screen) => Some(screen),
+            Err(err) => fail(&format!("Failed to open window: {}", err)),
+        }
+    };
+    // The scopes are only a debugging aid, emulation goes on without them
+    let scope: Option<ScopeWindow> = if show_scope && !headless {
+        match ScopeWindow::new(&format!("Audio channels - 1-4 mute, Shift+1-4 solo - {}", title)) {
+            Ok(window) => Some(window),
+            Err(err) => {
+                eprintln!("Failed to open the scope window, running without it: {}", err);
+                None
+            },
+        }
+    } else {
+        None
+    };
 
     //attach necessary items to the bus
-    let bus: Bus = Bus::new(rom, io, gpu);
+    let bus: Bus = Bus::new(rom, io, gpu, model);
 
     //give cpu access to bus and run the rom
     let mut cpu: CPU = CPU::new(bus);
-    let mut running: bool = true;
-    while running {
+    if let Some(path) = &record_path {
+        if let Err(err) = cpu.start_recording(path, record_channels) {
+            fail(&format!("Failed to start audio recording {}: {}", path.display(), err));
+        }
+    }
+    match screen {
+        Some(screen) => run_window(&mut cpu, screen, scope, audio, frames),
+        None => run_headless(&mut cpu, frames.unwrap_or(0)),
+    }
+    let recording: std::io::Result<()> = cpu.stop_recording();
+    // Exiting skips destructors, so the cartridge save has to be written first
+    drop(cpu);
+    if let Err(err) = recording {
+        fail(&format!("Failed to finish audio recording: {}", err));
+    }
+}
+
+/// Run with the window until it is closed or `frames` frames have been shown
+fn run_window(cpu: &mut CPU, mut screen: Screen, mut scope: Option<ScopeWindow>, audio: Option<Audio>, frames: Option<u64>) {
+    let mut steps: u64 = 0;
+    let mut frame: u64 = 0;
+    // Closing the window ends emulation, dropping the cartridge writes its save file
+    while screen.is_open() && frames.map_or(true, |frames| frame < frames) {
         cpu.run();
+        steps += 1;
+        if steps % SAVE_INTERVAL == 0 {
+            if let Err(err) = cpu.save_cart() {
+                eprintln!("Failed to write save file: {}", err);
+            }
+        }
+        for event in cpu.take_events() {
+            match event {
+                Event::Rumble(on) => screen.set_rumble(on),
+                Event::Lockup { opcode, pc } => screen.set_lockup(opcode, pc),
+                Event::Frame => {
+                    let samples: Vec<f32> = cpu.take_samples();
+                    if let Some(audio) = &audio {
+                        audio.queue(&samples);
+                    }
+                    screen.draw(cpu.framebuffer());
+                    feed_buttons(&mut screen, cpu);
+                    update_scope(&mut scope, cpu);
+                    frame += 1;
+                },
+            }
+        }
+        if cpu.stopped() {
+            screen.update();
+            feed_buttons(&mut screen, cpu);
+        }
     }
-    
 }
 
+/// Run `frames` frames as fast as possible with no window or audio device, for recording
+/// audio and for scripted runs
+fn run_headless(cpu: &mut CPU, frames: u64) {
+    let mut frame: u64 = 0;
+    while frame < frames {
+        cpu.run();
+        for event in cpu.take_events() {
+            match event {
+                Event::Rumble(_) => {},
+                Event::Lockup { opcode, pc } => eprintln!("CPU locked up on opcode {:02X} at {:04X}", opcode, pc),
+                Event::Frame => {
+                    // Nothing plays the samples, they only go to the recording
+                    cpu.take_samples();
+                    frame += 1;
+                },
+            }
+        }
+        if cpu.stopped() {
+            // With no buttons to press nothing can wake the CPU again
+            eprintln!("CPU stopped after {} frames with no input to wake it", frame);
+            return;
+        }
+    }
+}
+
+/// Redraw the oscilloscopes and apply mute and solo changes, closing the window only drops the scopes
+fn update_scope(scope: &mut Option<ScopeWindow>, cpu: &mut CPU) {
+    let window: &mut ScopeWindow = match scope {
+        Some(window) => window,
+        None => return,
+    };
+    if !window.is_open() {
+        *scope = None;
+        return;
+    }
+    let scopes: [Vec<f32>; CHANNELS] = std::array::from_fn(|channel| cpu.channel_scope(channel));
+    window.draw(&scopes);
+    if let Some((muted, soloed)) = window.poll_controls() {
+        for channel in 0..CHANNELS {
+            cpu.set_channel_muted(channel, muted[channel]);
+            cpu.set_channel_solo(channel, soloed[channel]);
+        }
+    }
+}
+
+/// Report an error that keeps the emulator from starting and exit with a failure status
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Pass keyboard changes seen by the window on to the joypad
+fn feed_buttons(screen: &mut Screen, cpu: &mut CPU) {
+    for (button, pressed) in screen.poll_buttons() {
+        cpu.set_button(button, pressed);
+    }
+}
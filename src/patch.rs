@@ -0,0 +1,497 @@
+////////////////
+/// 
+/// patch.rs
+/// 
+/// Sources:
+/// https://zerosoft.zophar.net/ips.php - IPS format
+/// https://www.romhacking.net/documents/746/ - BPS format specification
+/// https://www.romhacking.net/documents/392/ - UPS format specification
+/// 
+/// Soft-patching: the patch is applied to the ROM in memory at load time,
+/// the files on disk are never modified.
+/// 
+use std::fmt;
+
+/// Largest patched ROM accepted, twice the biggest MBC5 ROM. Sizes come from the patch
+/// and are allocated up front, so a corrupt one mustn't ask for more than this
+const MAX_TARGET_SIZE: usize = 0x100_0000;
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The file doesn't start with a known patch header
+    UnknownFormat,
+    /// The patch ends early or holds a record that points outside the ROM
+    Malformed(String),
+    /// The patch was made for a different ROM (BPS/UPS store the CRC32 of the source)
+    SourceMismatch { expected: u32, actual: u32 },
+    /// The patched ROM doesn't match the CRC32 stored in the patch
+    TargetMismatch { expected: u32, actual: u32 },
+    /// The patch file itself is corrupt
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, BPS or UPS patch"),
+            PatchError::Malformed(reason) => write!(f, "malformed patch: {}", reason),
+            PatchError::SourceMismatch { expected, actual } =>
+                write!(f, "patch is for a different ROM (expected CRC32 {:08X}, ROM is {:08X})", expected, actual),
+            PatchError::TargetMismatch { expected, actual } =>
+                write!(f, "patched ROM has CRC32 {:08X}, patch expects {:08X}", actual, expected),
+            PatchError::PatchChecksum { expected, actual } =>
+                write!(f, "patch file is corrupt (CRC32 {:08X}, expected {:08X})", actual, expected),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Apply an IPS, BPS or UPS patch to `rom`, picking the format from the patch header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        return apply_ips(rom, patch);
+    }
+    if patch.starts_with(b"BPS1") {
+        return apply_bps(rom, patch);
+    }
+    if patch.starts_with(b"UPS1") {
+        return apply_ups(rom, patch);
+    }
+    return Err(PatchError::UnknownFormat);
+}
+
+/// IPS
+/// "PATCH" followed by records until "EOF":
+///     3 byte offset, 2 byte size, then size bytes of data
+///     size 0 is a run: 2 byte count and 1 byte value
+/// An optional 3 byte length after "EOF" truncates the ROM.
+/// All numbers are big-endian, there are no checksums.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target: Vec<u8> = rom.to_vec();
+    let mut reader: Reader = Reader::new(patch, 5);
+    loop {
+        if reader.remaining().starts_with(b"EOF") {
+            reader.pos += 3;
+            break;
+        }
+        let offset: usize = reader.be(3)?;
+        let size: usize = reader.be(2)?;
+        if size == 0 {
+            let count: usize = reader.be(2)?;
+            let value: u8 = reader.byte()?;
+            write_at(&mut target, offset, &vec![value; count]);
+        }
+        else {
+            let data: &[u8] = reader.bytes(size)?;
+            write_at(&mut target, offset, data);
+        }
+    }
+    if reader.remaining().len() >= 3 {
+        let length: usize = reader.be(3)?;
+        target.truncate(length);
+    }
+    return Ok(target);
+}
+
+/// BPS
+/// "BPS1", source size, target size, metadata size and metadata, then actions until the footer.
+/// Each action is a number holding the command in the lower 2 bits and length - 1 above them:
+///     0 SourceRead - copy from the source at the current output offset
+///     1 TargetRead - copy bytes stored in the patch
+///     2 SourceCopy - copy from a relative offset in the source
+///     3 TargetCopy - copy from a relative offset in the output written so far
+/// Footer: source CRC32, target CRC32, patch CRC32 (little-endian)
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer: usize = check_footer(rom, patch)?;
+    let mut reader: Reader = Reader::new(&patch[..footer], 4);
+    let source_size: usize = reader.number()?;
+    let target_size: usize = reader.number()?;
+    let metadata_size: usize = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::Malformed(format!("expects a {} byte ROM, got {} bytes", source_size, rom.len())));
+    }
+    check_target_size(target_size)?;
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    while !reader.remaining().is_empty() {
+        let data: usize = reader.number()?;
+        let command: usize = data & 0b11;
+        let length: usize = (data >> 2) + 1;
+        if length > target_size - target.len() {
+            return Err(PatchError::Malformed(String::from("action writes past the target size")));
+        }
+        match command {
+            0 => {
+                let start: usize = target.len();
+                let bytes: &[u8] = match rom.get(start..start + length) {
+                    Some(bytes) => bytes,
+                    None => return Err(PatchError::Malformed(String::from("source read past the end of the ROM"))),
+                };
+                target.extend_from_slice(bytes);
+            },
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            2 => {
+                source_offset = offset_add(source_offset, reader.signed()?)?;
+                for _ in 0..length {
+                    match rom.get(source_offset as usize) {
+                        Some(byte) if source_offset >= 0 => target.push(*byte),
+                        _ => return Err(PatchError::Malformed(String::from("source copy outside the ROM"))),
+                    }
+                    source_offset += 1;
+                }
+            },
+            _ => {
+                target_offset = offset_add(target_offset, reader.signed()?)?;
+                // Byte by byte, the copy may overlap what it is writing
+                for _ in 0..length {
+                    if target_offset < 0 || target_offset as usize >= target.len() {
+                        return Err(PatchError::Malformed(String::from("target copy outside the output")));
+                    }
+                    target.push(target[target_offset as usize]);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Malformed(format!("produced {} bytes, expected {}", target.len(), target_size)));
+    }
+    check_target(patch, &target)?;
+    return Ok(target);
+}
+
+/// UPS
+/// "UPS1", source size, target size, then hunks until the footer:
+///     relative offset, then bytes XORed with the source up to and including a 0x00 terminator
+/// Footer: source CRC32, target CRC32, patch CRC32 (little-endian)
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer: usize = check_footer(rom, patch)?;
+    let mut reader: Reader = Reader::new(&patch[..footer], 4);
+    let source_size: usize = reader.number()?;
+    let target_size: usize = reader.number()?;
+    if source_size != rom.len() {
+        return Err(PatchError::Malformed(format!("expects a {} byte ROM, got {} bytes", source_size, rom.len())));
+    }
+    check_target_size(target_size)?;
+
+    let mut target: Vec<u8> = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut offset: usize = 0;
+    while !reader.remaining().is_empty() {
+        offset = match offset.checked_add(reader.number()?) {
+            Some(offset) => offset,
+            None => return Err(PatchError::Malformed(String::from("hunk offset overflows"))),
+        };
+        loop {
+            let x: u8 = reader.byte()?;
+            if offset < target.len() {
+                let source: u8 = if offset < rom.len() {rom[offset]} else {0};
+                target[offset] = source ^ x;
+            }
+            offset += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_target(patch, &target)?;
+    return Ok(target);
+}
+
+/// Verify the patch and source CRC32s in a BPS/UPS footer, returns where the footer starts
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<usize, PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Malformed(String::from("too short to hold a footer")));
+    }
+    let footer: usize = patch.len() - 12;
+
+    let expected: u32 = le32(&patch[footer + 8..]);
+    let actual: u32 = crc32fast::hash(&patch[..footer + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+    let expected: u32 = le32(&patch[footer..]);
+    let actual: u32 = crc32fast::hash(rom);
+    if expected != actual {
+        return Err(PatchError::SourceMismatch { expected, actual });
+    }
+    return Ok(footer);
+}
+
+fn check_target_size(target_size: usize) -> Result<(), PatchError> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::Malformed(format!("target size of {} bytes is too large", target_size)));
+    }
+    return Ok(());
+}
+
+/// Move a BPS copy offset, which may not leave the isize range
+fn offset_add(offset: isize, delta: isize) -> Result<isize, PatchError> {
+    match offset.checked_add(delta) {
+        Some(offset) => Ok(offset),
+        None => Err(PatchError::Malformed(String::from("copy offset overflows"))),
+    }
+}
+
+fn check_target(patch: &[u8], target: &[u8]) -> Result<(), PatchError> {
+    let expected: u32 = le32(&patch[patch.len() - 8..]);
+    let actual: u32 = crc32fast::hash(target);
+    if expected != actual {
+        return Err(PatchError::TargetMismatch { expected, actual });
+    }
+    return Ok(());
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Copy `data` into `target` at `offset`, growing the ROM if the patch writes past its end
+fn write_at(target: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if target.len() < offset + data.len() {
+        target.resize(offset + data.len(), 0);
+    }
+    target[offset..offset + data.len()].copy_from_slice(data);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], PatchError> {
+        if self.remaining().len() < count {
+            return Err(PatchError::Malformed(String::from("unexpected end of patch")));
+        }
+        let bytes: &'a [u8] = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        return Ok(bytes);
+    }
+
+    /// Big-endian number of `count` bytes (IPS)
+    fn be(&mut self, count: usize) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        for byte in self.bytes(count)? {
+            value = (value << 8) | *byte as usize;
+        }
+        return Ok(value);
+    }
+
+    /// Variable length number shared by BPS and UPS: 7 bits per byte, the high bit ends it.
+    /// A number too large for usize is malformed
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let too_large = || PatchError::Malformed(String::from("number too large"));
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x: u8 = self.byte()?;
+            let add: usize = ((x & 0x7F) as usize).checked_mul(shift).ok_or_else(too_large)?;
+            value = value.checked_add(add).ok_or_else(too_large)?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(too_large)?;
+            value = value.checked_add(shift).ok_or_else(too_large)?;
+        }
+    }
+
+    /// BPS relative offset: sign in bit 0, magnitude above it
+    fn signed(&mut self) -> Result<isize, PatchError> {
+        let data: usize = self.number()?;
+        let magnitude: isize = (data >> 1) as isize;
+        return Ok(if data & 1 != 0 {-magnitude} else {magnitude});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a BPS/UPS variable length number
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        loop {
+            let x: u8 = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(0x80 | x);
+                return bytes;
+            }
+            bytes.push(x);
+            value -= 1;
+        }
+    }
+
+    /// Append the source, target and patch CRC32 footer
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc: u32 = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        return patch;
+    }
+
+    /// Encode a BPS action header
+    fn action(command: usize, length: usize) -> Vec<u8> {
+        return number(((length - 1) << 2) | command);
+    }
+
+    fn bps(source: &[u8], target_size: usize, actions: &[u8]) -> Vec<u8> {
+        let mut patch: Vec<u8> = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target_size));
+        patch.extend(number(0));
+        patch.extend_from_slice(actions);
+        return patch;
+    }
+
+    #[test]
+    fn number_round_trips() {
+        for value in [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 0x12345678] {
+            let bytes: Vec<u8> = number(value);
+            assert_eq!(Reader::new(&bytes, 0).number().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn number_past_usize_is_malformed() {
+        let bytes: [u8; 12] = [0x7F; 12];
+        assert!(matches!(Reader::new(&bytes, 0).number(), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn ips_record_and_run() {
+        let rom: Vec<u8> = vec![0; 8];
+        let mut patch: Vec<u8> = b"PATCH".to_vec();
+        // 2 bytes at 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // Run of 3 0xCC at 4
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0x00, 0xAA, 0xBB, 0x00, 0xCC, 0xCC, 0xCC, 0x00]);
+    }
+
+    #[test]
+    fn ips_grows_and_truncates() {
+        let rom: Vec<u8> = vec![0; 4];
+        let mut patch: Vec<u8> = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x05, 0x00, 0x01, 0x11]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0, 0, 0, 0, 0x11]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x02]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn ips_truncated_is_malformed() {
+        let rom: Vec<u8> = vec![0; 4];
+        let patch: Vec<u8> = b"PATCH\x00\x00\x01\x00\x04\xAA".to_vec();
+        assert!(matches!(apply(&rom, &patch), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        let target: Vec<u8> = vec![1, 2, 9, 3, 4, 4, 4, 4];
+        let mut actions: Vec<u8> = Vec::new();
+        // SourceRead 2
+        actions.extend(action(0, 2));
+        // TargetRead 1: 9
+        actions.extend(action(1, 1));
+        actions.push(9);
+        // SourceCopy 2 from +2
+        actions.extend(action(2, 2));
+        actions.extend(number(2 << 1));
+        // TargetCopy 3 from +4, overlapping its own output
+        actions.extend(action(3, 3));
+        actions.extend(number(4 << 1));
+        let patch: Vec<u8> = finish(bps(&source, target.len(), &actions), &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        let actions: Vec<u8> = action(0, 4);
+        let patch: Vec<u8> = finish(bps(&source, 4, &actions), &source, &source);
+        assert!(apply(&source, &patch).is_ok());
+
+        assert!(matches!(apply(&[1, 2, 3, 5], &patch), Err(PatchError::SourceMismatch { .. })));
+
+        let wrong_target: Vec<u8> = finish(bps(&source, 4, &actions), &source, &[0, 0, 0, 0]);
+        assert!(matches!(apply(&source, &wrong_target), Err(PatchError::TargetMismatch { .. })));
+
+        let mut corrupt: Vec<u8> = patch.clone();
+        corrupt[4] ^= 0x01;
+        assert!(matches!(apply(&source, &corrupt), Err(PatchError::PatchChecksum { .. })));
+    }
+
+    #[test]
+    fn bps_rejects_huge_target_size() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        let patch: Vec<u8> = finish(bps(&source, usize::MAX >> 8, &[]), &source, &source);
+        assert!(matches!(apply(&source, &patch), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn bps_truncated_is_malformed() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        // TargetRead 4 with only 1 byte behind it
+        let mut actions: Vec<u8> = action(1, 4);
+        actions.push(9);
+        let patch: Vec<u8> = finish(bps(&source, 4, &actions), &source, &source);
+        assert!(matches!(apply(&source, &patch), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn ups_hunks() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        let target: Vec<u8> = vec![1, 7, 3, 4, 5];
+        let mut patch: Vec<u8> = b"UPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        // Skip 1, XOR 2 -> 7, terminator
+        patch.extend(number(1));
+        patch.extend_from_slice(&[2 ^ 7, 0x00]);
+        // Skip 1 more, past the source end 0 -> 5, terminator
+        patch.extend(number(1));
+        patch.extend_from_slice(&[5, 0x00]);
+        let patch: Vec<u8> = finish(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_truncated_is_malformed() {
+        let source: Vec<u8> = vec![1, 2, 3, 4];
+        let mut patch: Vec<u8> = b"UPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(source.len()));
+        // Hunk without its 0x00 terminator
+        patch.extend(number(0));
+        patch.push(0x05);
+        let patch: Vec<u8> = finish(patch, &source, &source);
+        assert!(matches!(apply(&source, &patch), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(matches!(apply(&[0; 4], b"NOPE"), Err(PatchError::UnknownFormat)));
+    }
+}
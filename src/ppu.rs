@@ -1,6 +1,152 @@
+////////////////
+/// 
+/// ppu.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/Rendering.html - PPU modes
+/// https://gbdev.io/pandocs/STAT.html - LY, LYC and STAT
+/// https://gbdev.io/pandocs/LCDC.html - LCD control
+/// https://gbdev.io/pandocs/OAM.html - sprite attributes and priority
+/// https://gbdev.io/pandocs/Tile_Maps.html - tile maps and addressing modes
+/// https://gbdev.io/pandocs/pixel_fifo.html - FIFO and fetcher
+/// https://github.com/mattcurrie/dmg-acid2 - rendering test
+/// https://github.com/Gekkio/mooneye-test-suite - PPU timing tests
+/// 
+/// A frame is 154 lines of 456 dots, 4 dots per machine cycle.
+/// Lines 0 - 143 go through
+///     mode 2 OAM scan   dots 0 - 79
+///     mode 3 drawing    dots 80 - 251
+///     mode 0 HBlank     the rest of the line
+/// Lines 144 - 153 are mode 1 VBlank.
+/// 
+/// 0xFF40 : LCDC
+/// 0xFF41 : STAT - bit 6 LYC, 5 mode 2, 4 mode 1, 3 mode 0 interrupt select
+///                 bit 2 LY == LYC, bits 0-1 mode
+/// 0xFF42 : SCY    0xFF43 : SCX
+/// 0xFF44 : LY     0xFF45 : LYC
+/// 0xFF47 : BGP    0xFF48 : OBP0    0xFF49 : OBP1
+/// 0xFF4A : WY     0xFF4B : WX
+/// 
+/// Two renderers, picked at startup:
+///     Scanline - each line is drawn in one go when mode 3 ends, from the decoded tile
+///                cache, so register changes made during mode 3 aren't seen and
+///                mode 3 is always 172 dots
+///     Fifo     - a pixel FIFO fed by a fetcher reading VRAM, one pixel per dot.
+///                Mode 3 takes 172 dots plus SCX % 8, about 6 when the window starts
+///                and 6 - 11 per sprite, and mid-line SCX, palette and LCDC writes show up
+/// 
+/// The STAT interrupt sources are ORed into a single line and the interrupt is only
+/// requested when that line goes from low to high. While one source holds it high,
+/// another source becoming true doesn't request a second interrupt (STAT blocking).
+/// 
+use std::collections::VecDeque;
+use crate::event::Event;
+use crate::interrupt::{Interrupt, Interrupts};
+
 const VRAM_BEGIN: usize = 0x8000;
 const VRAM_END: usize = 0x9FFF;
 const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
+const OAM_SIZE: usize = 0xA0;
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const VBLANK_LINE: u8 = 144;
+const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+const SPRITES_PER_LINE: usize = 10;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// How lines are drawn, see the top of the file
+#[derive(Clone, Copy, PartialEq)]
+pub enum Renderer {
+    Scanline,
+    Fifo,
+}
+
+impl Renderer {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "scanline" => Some(Renderer::Scanline),
+            "fifo" => Some(Renderer::Fifo),
+            _ => None,
+        }
+    }
+}
+
+/// A sprite pixel waiting in the FIFO, color 0 is transparent
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    obp1: bool,
+    behind_bg: bool,
+}
+
+/*
+ * bg: background and window color numbers
+ * obj: sprite pixels lined up with the front of bg
+ * step: dots into the current background fetch, 2 per step (tile number, data low, data high) then push
+ * tile_x: tile column being fetched
+ * tile, row, low: fetched tile number, its row and the low data byte
+ * high: the high data byte
+ * first_fetch: the first fetch of every line is thrown away
+ * discard: pixels still to be dropped for SCX fine scroll
+ * lx: next pixel on the line
+ * window: fetching from the window map
+ * sprites: sprites from the OAM scan that haven't been fetched yet
+ * sprite: sprite being fetched, the FIFO doesn't shift until it's done
+ * sprite_dots: dots left on the sprite fetch
+ * sprite_tile: last background tile column a sprite waited on the fetcher in
+ */
+struct Fifo {
+    bg: VecDeque<u8>,
+    obj: VecDeque<ObjPixel>,
+    step: u8,
+    tile_x: u8,
+    tile: u8,
+    row: u8,
+    low: u8,
+    high: u8,
+    first_fetch: bool,
+    discard: u8,
+    lx: usize,
+    window: bool,
+    sprites: Vec<usize>,
+    sprite: Option<usize>,
+    sprite_dots: u8,
+    sprite_tile: Option<usize>,
+}
+
+impl Fifo {
+    fn new() -> Self {
+        Self {
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: 0,
+            tile_x: 0,
+            tile: 0,
+            row: 0,
+            low: 0,
+            high: 0,
+            first_fetch: true,
+            discard: 0,
+            lx: 0,
+            window: false,
+            sprites: Vec::new(),
+            sprite: None,
+            sprite_dots: 0,
+            sprite_tile: None,
+        }
+    }
+}
 
 #[derive(Copy,Clone)]
 enum TilePixelValue {
@@ -15,14 +161,529 @@ fn empty_tile() -> Tile {
     [[TilePixelValue::Zero; 8]; 8]
 }
 
+/// Map a color number through BGP, OBP0 or OBP1
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+/*
+ * vram: tile data and maps, 0x8000 - 0x9FFF
+ * tile_set: decoded copy of the tile data
+ * oam: sprite attributes, 40 sprites of 4 bytes at 0xFE00 - 0xFE9F
+ * mode: current PPU mode
+ * dot: position in the current line, 0 - 455
+ * stat_line: the ORed STAT interrupt sources, an interrupt is requested on its rising edge
+ * stat_select: STAT bits 3-6
+ * framebuffer: 160x144 shades, 0 white to 3 black, after the palettes
+ * window_triggered: LY matched WY this frame, so the window can show up
+ * window_line: internal line counter of the window, only advances on lines the window is drawn
+ * renderer: which renderer draws the lines
+ * fifo: state of the FIFO renderer during mode 3
+ * off_dots: dots since the last blank frame while the LCD is off
+ * events: frames completed since the frontend last looked
+ */
 pub struct GPU{
     vram: [u8; VRAM_SIZE],
     tile_set: [Tile; 384],
+    oam: [u8; OAM_SIZE],
+    mode: Mode,
+    dot: u16,
+    stat_line: bool,
+    lcdc: u8,
+    stat_select: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    window_triggered: bool,
+    window_line: u8,
+    renderer: Renderer,
+    fifo: Fifo,
+    off_dots: u32,
+    events: Vec<Event>,
 }
 
 impl GPU{
-    pub fn new()-> Self{
-        Self { vram: [0; VRAM_SIZE], tile_set: [empty_tile(); 384] }
+    pub fn new(renderer: Renderer)-> Self{
+        Self {
+            vram: [0; VRAM_SIZE],
+            tile_set: [empty_tile(); 384],
+            oam: [0; OAM_SIZE],
+            mode: Mode::OamScan,
+            dot: 0,
+            stat_line: false,
+            // State left behind by the DMG boot ROM
+            lcdc: 0x91,
+            stat_select: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            window_triggered: false,
+            window_line: 0,
+            renderer,
+            fifo: Fifo::new(),
+            off_dots: 0,
+            events: Vec::new(),
+        }
+    }
+
+    fn lcd_on(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    /// Advance by one machine cycle
+    pub fn tick(&mut self, interrupts: &mut Interrupts){
+        if !self.lcd_on() {
+            // Keep handing out (blank) frames so the frontend stays responsive
+            self.off_dots += 4;
+            if self.off_dots >= DOTS_PER_FRAME {
+                self.off_dots = 0;
+                self.events.push(Event::Frame);
+            }
+            return;
+        }
+        for _ in 0..4 {
+            self.dot_tick(interrupts);
+        }
+    }
+
+    fn dot_tick(&mut self, interrupts: &mut Interrupts){
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+            }
+            if self.ly == VBLANK_LINE {
+                self.mode = Mode::VBlank;
+                self.window_triggered = false;
+                self.window_line = 0;
+                interrupts.request(Interrupt::VBlank);
+                self.events.push(Event::Frame);
+            }
+            else if self.ly < VBLANK_LINE {
+                self.start_line();
+            }
+        }
+        else if self.ly < VBLANK_LINE {
+            match self.renderer {
+                Renderer::Scanline => {
+                    if self.dot == OAM_SCAN_DOTS {
+                        self.mode = Mode::Drawing;
+                    }
+                    else if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                        self.render_line();
+                        self.mode = Mode::HBlank;
+                    }
+                },
+                Renderer::Fifo => {
+                    if self.dot == OAM_SCAN_DOTS {
+                        self.mode = Mode::Drawing;
+                        self.fifo_start();
+                    }
+                    if self.mode == Mode::Drawing {
+                        if self.fifo.lx == SCREEN_WIDTH {
+                            if self.fifo.window {
+                                self.window_line += 1;
+                            }
+                            self.mode = Mode::HBlank;
+                        }
+                        else {
+                            self.fifo_dot();
+                        }
+                    }
+                },
+            }
+        }
+        self.update_stat(interrupts);
+    }
+
+    /// Recompute the STAT interrupt line and request on a rising edge
+    fn update_stat(&mut self, interrupts: &mut Interrupts){
+        let mut line: bool = self.ly == self.lyc && self.stat_select & 0x40 != 0;
+        line |= match self.mode {
+            Mode::HBlank => self.stat_select & 0x08 != 0,
+            // The mode 2 source also fires at the start of VBlank
+            Mode::VBlank => self.stat_select & 0x10 != 0
+                || (self.ly == VBLANK_LINE && self.dot == 0 && self.stat_select & 0x20 != 0),
+            Mode::OamScan => self.stat_select & 0x20 != 0,
+            Mode::Drawing => false,
+        };
+        line &= self.lcd_on();
+        if line && !self.stat_line {
+            interrupts.request(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    fn read_stat(&self) -> u8 {
+        if !self.lcd_on() {
+            return 0x80 | self.stat_select;
+        }
+        let coincidence: u8 = if self.ly == self.lyc {0x04} else {0x00};
+        return 0x80 | self.stat_select | coincidence | self.mode as u8;
+    }
+
+    /// LCD registers 0xFF40 - 0xFF4B, except 0xFF46 which is the DMA on the bus
+    pub fn write_reg(&mut self, addr: u16, data: u8, interrupts: &mut Interrupts){
+        match addr {
+            0xFF40 => {
+                let was_on: bool = self.lcd_on();
+                self.lcdc = data;
+                if was_on && !self.lcd_on() {
+                    // Turning the LCD off resets LY and parks the PPU in HBlank, the screen goes blank
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.mode = Mode::HBlank;
+                    self.framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+                }
+                else if !was_on && self.lcd_on() {
+                    self.window_triggered = false;
+                    self.window_line = 0;
+                    self.start_line();
+                }
+            },
+            0xFF41 => self.stat_select = data & 0x78,
+            0xFF42 => self.scy = data,
+            0xFF43 => self.scx = data,
+            // LY is read only
+            0xFF44 => {},
+            0xFF45 => self.lyc = data,
+            0xFF47 => self.bgp = data,
+            0xFF48 => self.obp0 = data,
+            0xFF49 => self.obp1 = data,
+            0xFF4A => self.wy = data,
+            0xFF4B => self.wx = data,
+            _ => {},
+        }
+        self.update_stat(interrupts);
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => self.read_stat(),
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            _ => 0xFF,
+        }
+    }
+    /// The last completed frame
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        return std::mem::take(&mut self.events);
+    }
+
+    /// Start of mode 2 on a visible line
+    fn start_line(&mut self){
+        self.mode = Mode::OamScan;
+        if self.ly == self.wy {
+            self.window_triggered = true;
+        }
+    }
+
+    /// Tile data number in tile_set for a tile map entry.
+    /// LCDC bit 4 set: 0x8000 unsigned, clear: 0x9000 signed
+    fn tile_number(&self, index: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            return index as usize;
+        }
+        return (256 + (index as i8) as i16) as usize;
+    }
+
+    /// Color number of a background or window pixel, map is 0x1800 or 0x1C00
+    fn map_pixel(&self, map: usize, x: u8, y: u8) -> u8 {
+        let index: u8 = self.vram[map + (y as usize / 8) * 32 + x as usize / 8];
+        let tile: &Tile = &self.tile_set[self.tile_number(index)];
+        return tile[y as usize % 8][x as usize % 8] as u8;
+    }
+
+    /// Draw line LY: background, then window, then sprites
+    fn render_line(&mut self){
+        // Color numbers before the palette, sprites need them for BG priority
+        let mut line: [u8; SCREEN_WIDTH] = [0; SCREEN_WIDTH];
+
+        // On DMG LCDC bit 0 blanks both background and window
+        if self.lcdc & 0x01 != 0 {
+            let bg_map: usize = if self.lcdc & 0x08 != 0 {0x1C00} else {0x1800};
+            let y: u8 = self.ly.wrapping_add(self.scy);
+            for x in 0..SCREEN_WIDTH {
+                line[x] = self.map_pixel(bg_map, (x as u8).wrapping_add(self.scx), y);
+            }
+
+            // WX is the window position plus 7
+            let window_x: i16 = self.wx as i16 - 7;
+            if self.lcdc & 0x20 != 0 && self.window_triggered && window_x < SCREEN_WIDTH as i16 {
+                let win_map: usize = if self.lcdc & 0x40 != 0 {0x1C00} else {0x1800};
+                for x in window_x.max(0) as usize..SCREEN_WIDTH {
+                    line[x] = self.map_pixel(win_map, (x as i16 - window_x) as u8, self.window_line);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let row: usize = self.ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            self.framebuffer[row + x] = shade(self.bgp, line[x]);
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(&line);
+        }
+    }
+
+    /// OAM entry: Y + 16, X + 8, tile, attributes
+    /// attributes bit 7 behind BG colors 1-3, bit 6 Y flip, bit 5 X flip, bit 4 OBP1
+    fn render_sprites(&mut self, line: &[u8; SCREEN_WIDTH]){
+        let height: i16 = if self.lcdc & 0x04 != 0 {16} else {8};
+        let ly: i16 = self.ly as i16;
+
+        let mut sprites: Vec<usize> = self.scan_oam();
+        // DMG priority: smaller X wins, then the earlier OAM entry. Stable sort keeps the OAM order
+        sprites.sort_by_key(|i| self.oam[i * 4 + 1]);
+
+        let row: usize = self.ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH as i16 {
+            for i in sprites.iter() {
+                let sprite_x: i16 = self.oam[i * 4 + 1] as i16 - 8;
+                if x < sprite_x || x >= sprite_x + 8 {
+                    continue;
+                }
+                let attr: u8 = self.oam[i * 4 + 3];
+                let mut tile_y: i16 = ly - (self.oam[i * 4] as i16 - 16);
+                if attr & 0x40 != 0 {
+                    tile_y = height - 1 - tile_y;
+                }
+                let mut tile_x: i16 = x - sprite_x;
+                if attr & 0x20 != 0 {
+                    tile_x = 7 - tile_x;
+                }
+                // 8x16 sprites ignore bit 0 of the tile number
+                let mut tile: usize = self.oam[i * 4 + 2] as usize;
+                if height == 16 {
+                    tile &= 0xFE;
+                }
+                tile += tile_y as usize / 8;
+                let color: u8 = self.tile_set[tile][tile_y as usize % 8][tile_x as usize] as u8;
+                // Color 0 is transparent, a lower priority sprite may still show through
+                if color == 0 {
+                    continue;
+                }
+                if attr & 0x80 == 0 || line[x as usize] == 0 {
+                    let palette: u8 = if attr & 0x10 != 0 {self.obp1} else {self.obp0};
+                    self.framebuffer[row + x as usize] = shade(palette, color);
+                }
+                break;
+            }
+        }
+    }
+
+    /// OAM scan: the first 10 sprites in OAM order that overlap this line, even ones off screen horizontally
+    fn scan_oam(&self) -> Vec<usize> {
+        let height: i16 = if self.lcdc & 0x04 != 0 {16} else {8};
+        let ly: i16 = self.ly as i16;
+        let mut sprites: Vec<usize> = Vec::with_capacity(SPRITES_PER_LINE);
+        for i in 0..40 {
+            let y: i16 = self.oam[i * 4] as i16 - 16;
+            if ly >= y && ly < y + height {
+                sprites.push(i);
+                if sprites.len() == SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+        return sprites;
+    }
+
+    /// Start of mode 3 with the FIFO renderer
+    fn fifo_start(&mut self){
+        self.fifo = Fifo::new();
+        if self.lcdc & 0x02 != 0 {
+            self.fifo.sprites = self.scan_oam();
+        }
+        self.fifo.discard = self.scx & 7;
+    }
+
+    /// One dot of mode 3 with the FIFO renderer
+    fn fifo_dot(&mut self){
+        let lx: i16 = self.fifo.lx as i16;
+
+        // Reaching WX restarts the fetcher on the window map with an empty FIFO. The SCX fine
+        // scroll doesn't apply to the window, but a WX below 7 drops its first 7 - WX pixels
+        if !self.fifo.window && self.lcdc & 0x20 != 0 && self.window_triggered && lx >= self.wx as i16 - 7 {
+            self.fifo.window = true;
+            self.fifo.bg.clear();
+            self.fifo.tile_x = 0;
+            self.fifo.step = 0;
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        // A sprite starting at this pixel stops the FIFO, lowest X first then OAM order
+        if self.fifo.sprite.is_none() && self.lcdc & 0x02 != 0 {
+            let next: Option<usize> = self.fifo.sprites.iter()
+                .enumerate()
+                .filter(|(_, i)| self.oam[*i * 4 + 1] as i16 - 8 <= lx)
+                .min_by_key(|(_, i)| self.oam[*i * 4 + 1])
+                .map(|(pos, _)| pos);
+            if let Some(pos) = next {
+                let i: usize = self.fifo.sprites.remove(pos);
+                // The sprite fetch takes 6 dots, plus a wait for the background fetch of the tile
+                // the sprite starts in, 5 dots at the start of the tile and none from its 6th pixel.
+                // Only the first sprite in a tile waits
+                let x: usize = (self.oam[i * 4 + 1] as i16 - 8).max(0) as usize + (self.scx & 7) as usize;
+                let wait: u8 = if self.fifo.sprite_tile == Some(x / 8) {0} else {5u8.saturating_sub((x % 8) as u8)};
+                self.fifo.sprite_tile = Some(x / 8);
+                self.fifo.sprite = Some(i);
+                self.fifo.sprite_dots = 6 + wait;
+            }
+        }
+        if let Some(i) = self.fifo.sprite {
+            // The background fetcher carries on while the FIFO is stopped
+            self.fetcher_dot();
+            self.fifo.sprite_dots -= 1;
+            if self.fifo.sprite_dots == 0 {
+                self.load_sprite(i);
+                self.fifo.sprite = None;
+            }
+            return;
+        }
+
+        self.fetcher_dot();
+        self.shift_pixel();
+    }
+
+    /// Background/window fetcher, each step takes 2 dots and the push is retried every dot
+    fn fetcher_dot(&mut self){
+        self.fifo.step += 1;
+        match self.fifo.step {
+            2 => {
+                let (map_bit, x, y): (u8, u8, u8) = if self.fifo.window {
+                    (0x40, self.fifo.tile_x, self.window_line)
+                }
+                else {
+                    (0x08, ((self.scx / 8) + self.fifo.tile_x) & 31, self.ly.wrapping_add(self.scy))
+                };
+                let map: usize = if self.lcdc & map_bit != 0 {0x1C00} else {0x1800};
+                self.fifo.tile = self.vram[map + (y as usize / 8) * 32 + x as usize];
+                self.fifo.row = y % 8;
+            },
+            4 => self.fifo.low = self.tile_data(0),
+            6 => {
+                self.fifo.high = self.tile_data(1);
+                if self.fifo.first_fetch {
+                    self.fifo.first_fetch = false;
+                    self.fifo.step = 0;
+                }
+            },
+            7.. => {
+                if self.fifo.bg.is_empty() {
+                    for bit in (0..8).rev() {
+                        let color: u8 = ((self.fifo.high >> bit) & 1) << 1 | ((self.fifo.low >> bit) & 1);
+                        self.fifo.bg.push_back(color);
+                    }
+                    self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+                    self.fifo.step = 0;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Low (0) or high (1) byte of the fetched tile row, addressed through LCDC bit 4
+    fn tile_data(&self, byte: usize) -> u8 {
+        let tile: usize = self.tile_number(self.fifo.tile);
+        return self.vram[tile * 16 + self.fifo.row as usize * 2 + byte];
+    }
+
+    /// Mix the sprite pixels into the sprite FIFO, pixels already there from an earlier sprite win
+    fn load_sprite(&mut self, i: usize){
+        let height: i16 = if self.lcdc & 0x04 != 0 {16} else {8};
+        let attr: u8 = self.oam[i * 4 + 3];
+        let mut tile_y: i16 = self.ly as i16 - (self.oam[i * 4] as i16 - 16);
+        if attr & 0x40 != 0 {
+            tile_y = height - 1 - tile_y;
+        }
+        let mut tile: usize = self.oam[i * 4 + 2] as usize;
+        if height == 16 {
+            tile &= 0xFE;
+        }
+        let addr: usize = tile * 16 + tile_y as usize * 2;
+        let low: u8 = self.vram[addr];
+        let high: u8 = self.vram[addr + 1];
+
+        // Sprites hanging off the left edge only load their visible part
+        let skip: i16 = (self.fifo.lx as i16 - (self.oam[i * 4 + 1] as i16 - 8)).max(0);
+        for px in skip..8 {
+            let bit: i16 = if attr & 0x20 != 0 {px} else {7 - px};
+            let pixel: ObjPixel = ObjPixel {
+                color: ((high >> bit) & 1) << 1 | ((low >> bit) & 1),
+                obp1: attr & 0x10 != 0,
+                behind_bg: attr & 0x80 != 0,
+            };
+            let slot: usize = (px - skip) as usize;
+            if slot >= self.fifo.obj.len() {
+                self.fifo.obj.push_back(pixel);
+            }
+            else if self.fifo.obj[slot].color == 0 {
+                self.fifo.obj[slot] = pixel;
+            }
+        }
+    }
+
+    /// Output one pixel, the palettes and LCDC are read right now
+    fn shift_pixel(&mut self){
+        let bg: u8 = match self.fifo.bg.pop_front() {
+            Some(color) => color,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let obj: Option<ObjPixel> = self.fifo.obj.pop_front();
+
+        let bg: u8 = if self.lcdc & 0x01 != 0 {bg} else {0};
+        let mut value: u8 = shade(self.bgp, bg);
+        if let Some(pixel) = obj {
+            if pixel.color != 0 && self.lcdc & 0x02 != 0 && !(pixel.behind_bg && bg != 0) {
+                let palette: u8 = if pixel.obp1 {self.obp1} else {self.obp0};
+                value = shade(palette, pixel.color);
+            }
+        }
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + self.fifo.lx] = value;
+        self.fifo.lx += 1;
+    }
+
+    /// addr is relative to 0xFE00
+    pub fn write_oam(&mut self, addr: u16, data: u8){
+        self.oam[addr as usize] = data;
+    }
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        self.oam[addr as usize]
     }
     pub fn write(&mut self, addr: u16, data: u8){
         self.vram[addr as usize] = data;
@@ -87,4 +748,393 @@ impl GPU{
     pub fn read_vram(&self, address: u16) -> u8 {
         self.vram[address as usize]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Palette that maps each color number to the same shade
+    const IDENTITY: u8 = 0xE4;
+    /// Tile bytes whose columns count through colors 0, 1, 2, 3, 0, 1, 2, 3
+    const COLUMNS: (u8, u8) = (0x55, 0x33);
+    /// LCD, window with the 0x9C00 map, tile data at 0x8000, sprites and background on
+    const LCDC: u8 = 0xF3;
+
+    /// PPU at the start of line 0 with identity palettes
+    fn gpu(renderer: Renderer) -> GPU {
+        let mut gpu: GPU = GPU::new(renderer);
+        gpu.bgp = IDENTITY;
+        gpu.obp0 = IDENTITY;
+        gpu.obp1 = IDENTITY;
+        return gpu;
+    }
+
+    /// Tile bytes for a tile of a single color
+    fn solid(color: u8) -> (u8, u8) {
+        return (if color & 1 != 0 {0xFF} else {0x00}, if color & 2 != 0 {0xFF} else {0x00});
+    }
+
+    /// Give every row of tile `tile` at 0x8000 the same two bytes
+    fn set_tile(gpu: &mut GPU, tile: u16, (low, high): (u8, u8)) {
+        for row in 0..8 {
+            gpu.write(tile * 16 + row * 2, low);
+            gpu.write(tile * 16 + row * 2 + 1, high);
+        }
+    }
+
+    fn set_sprite(gpu: &mut GPU, index: u16, y: u8, x: u8, tile: u8, attr: u8) {
+        for (i, byte) in [y, x, tile, attr].iter().enumerate() {
+            gpu.write_oam(index * 4 + i as u16, *byte);
+        }
+    }
+
+    /// Run dots until the visible lines are drawn
+    fn draw_frame(gpu: &mut GPU, interrupts: &mut Interrupts) {
+        while gpu.ly < VBLANK_LINE {
+            gpu.dot_tick(interrupts);
+        }
+    }
+
+    fn line(gpu: &GPU, ly: usize) -> &[u8] {
+        return &gpu.framebuffer[ly * SCREEN_WIDTH..(ly + 1) * SCREEN_WIDTH];
+    }
+
+    /// Scrolled background of three tiles, a window and overlapping, flipped, hidden and clipped sprites
+    fn scene(gpu: &mut GPU) {
+        gpu.lcdc = LCDC;
+        set_tile(gpu, 0, solid(1));
+        set_tile(gpu, 1, COLUMNS);
+        set_tile(gpu, 2, (0x0F, 0xF0));
+        for i in 0..0x400 {
+            gpu.write(0x1800 + i, (i % 3) as u8);
+            gpu.write(0x1C00 + i, ((i + 1) % 3) as u8);
+        }
+        gpu.scx = 3;
+        gpu.scy = 5;
+        gpu.wx = 50;
+        gpu.wy = 40;
+        gpu.obp1 = 0x1B;
+        set_sprite(gpu, 0, 16 + 10, 8 + 20, 2, 0x00);
+        set_sprite(gpu, 1, 16 + 12, 8 + 24, 1, 0x20 | 0x10);
+        set_sprite(gpu, 2, 16 + 60, 8 + 100, 1, 0x80);
+        set_sprite(gpu, 3, 16 + 20, 4, 2, 0x40);
+    }
+
+    #[test]
+    fn fifo_matches_scanline_on_a_still_frame() {
+        let mut scanline: GPU = gpu(Renderer::Scanline);
+        let mut fifo: GPU = gpu(Renderer::Fifo);
+        scene(&mut scanline);
+        scene(&mut fifo);
+        draw_frame(&mut scanline, &mut Interrupts::new());
+        draw_frame(&mut fifo, &mut Interrupts::new());
+        for ly in 0..SCREEN_HEIGHT {
+            assert_eq!(line(&fifo, ly), line(&scanline, ly), "line {}", ly);
+        }
+    }
+
+    #[test]
+    fn fifo_window_ignores_fine_scroll() {
+        for renderer in [Renderer::Scanline, Renderer::Fifo] {
+            for wx in [0, 3, 7] {
+                let mut gpu: GPU = gpu(renderer);
+                gpu.lcdc = LCDC;
+                set_tile(&mut gpu, 0, solid(3));
+                set_tile(&mut gpu, 1, COLUMNS);
+                for i in 0..0x400 {
+                    gpu.write(0x1C00 + i, 1);
+                }
+                gpu.scx = 5;
+                gpu.wx = wx;
+                // WY = LY = 0 is only noticed when line 0 starts
+                gpu.start_line();
+                draw_frame(&mut gpu, &mut Interrupts::new());
+                // The window starts at X = WX - 7, anything left of 0 is cut off
+                let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| ((x + 7 - wx as usize) % 4) as u8).collect();
+                assert_eq!(line(&gpu, 0), &expected[..], "WX {}", wx);
+            }
+        }
+    }
+
+    #[test]
+    fn fifo_sees_mid_line_palette_writes() {
+        let mut gpu: GPU = gpu(Renderer::Fifo);
+        let mut interrupts: Interrupts = Interrupts::new();
+        set_tile(&mut gpu, 0, solid(1));
+        while gpu.fifo.lx < 80 || gpu.mode != Mode::Drawing {
+            gpu.dot_tick(&mut interrupts);
+        }
+        // Color 1 turns from shade 1 to shade 3 halfway through the line
+        gpu.write_reg(0xFF47, 0xEC, &mut interrupts);
+        draw_frame(&mut gpu, &mut interrupts);
+        let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| if x < 80 {1} else {3}).collect();
+        assert_eq!(line(&gpu, 0), &expected[..]);
+        assert!(line(&gpu, 1).iter().all(|pixel| *pixel == 3));
+    }
+
+    /// Dots spent in mode 3 on line 0
+    fn drawing_dots(gpu: &mut GPU) -> u16 {
+        let mut interrupts: Interrupts = Interrupts::new();
+        let mut dots: u16 = 0;
+        while gpu.ly == 0 {
+            gpu.dot_tick(&mut interrupts);
+            if gpu.mode == Mode::Drawing {
+                dots += 1;
+            }
+        }
+        return dots;
+    }
+
+    /// Mode 3 dots of the FIFO renderer with 8x8 sprites on line 0 at these screen X positions
+    fn fifo_sprite_dots(xs: &[u8]) -> u16 {
+        let mut gpu: GPU = gpu(Renderer::Fifo);
+        gpu.lcdc = LCDC;
+        for (i, x) in xs.iter().enumerate() {
+            set_sprite(&mut gpu, i as u16, 16, x + 8, 0, 0);
+        }
+        return drawing_dots(&mut gpu);
+    }
+
+    #[test]
+    fn fifo_mode_3_length() {
+        assert_eq!(fifo_sprite_dots(&[]), DRAWING_DOTS);
+        let mut scrolled: GPU = gpu(Renderer::Fifo);
+        scrolled.scx = 3;
+        assert_eq!(drawing_dots(&mut scrolled), DRAWING_DOTS + 3);
+        // A sprite at the start of a tile waits 5 dots for the background fetch, from the
+        // 6th pixel on it doesn't, and neither does a second sprite in the same tile
+        assert_eq!(fifo_sprite_dots(&[40]), DRAWING_DOTS + 11);
+        assert_eq!(fifo_sprite_dots(&[45]), DRAWING_DOTS + 6);
+        assert_eq!(fifo_sprite_dots(&[40, 41]), DRAWING_DOTS + 11 + 6);
+        // The scanline renderer always takes the same time
+        let mut scanline: GPU = gpu(Renderer::Scanline);
+        scanline.lcdc = LCDC;
+        scanline.scx = 3;
+        set_sprite(&mut scanline, 0, 16, 8 + 40, 0, 0);
+        assert_eq!(drawing_dots(&mut scanline), DRAWING_DOTS);
+    }
+
+    /// Run `dots` dots
+    fn run(gpu: &mut GPU, interrupts: &mut Interrupts, dots: u32) {
+        for _ in 0..dots {
+            gpu.dot_tick(interrupts);
+        }
+    }
+
+    /// Interrupts with nothing requested
+    fn no_interrupts() -> Interrupts {
+        let mut interrupts: Interrupts = Interrupts::new();
+        interrupts.write_if(0);
+        return interrupts;
+    }
+
+    fn stat_requested(interrupts: &Interrupts) -> bool {
+        return interrupts.read_if() & Interrupt::LcdStat.bit() != 0;
+    }
+
+    #[test]
+    fn modes_through_a_line_and_a_frame() {
+        let mut gpu: GPU = gpu(Renderer::Scanline);
+        let mut interrupts: Interrupts = no_interrupts();
+        // (dots from the start of line 0, LY, mode)
+        let steps: [(u32, u8, u8); 8] = [
+            (79, 0, 2),
+            (80, 0, 3),
+            (251, 0, 3),
+            (252, 0, 0),
+            (455, 0, 0),
+            (456, 1, 2),
+            (456 * 144 - 1, 143, 0),
+            (456 * 144, 144, 1),
+        ];
+        let mut dots: u32 = 0;
+        for (at, ly, mode) in steps {
+            run(&mut gpu, &mut interrupts, at - dots);
+            dots = at;
+            assert_eq!((gpu.read_reg(0xFF44), gpu.read_reg(0xFF41) & 0b11), (ly, mode), "dot {}", at);
+        }
+        assert_eq!(interrupts.read_if() & 0x1F, Interrupt::VBlank.bit());
+        // LY wraps after line 153 and drawing starts over
+        run(&mut gpu, &mut interrupts, 456 * 10);
+        assert_eq!((gpu.read_reg(0xFF44), gpu.read_reg(0xFF41) & 0b11), (0, 2));
+    }
+
+    #[test]
+    fn stat_interrupt_per_source() {
+        // (STAT select, dots before the first request)
+        let sources: [(u8, u32); 3] = [
+            (0x08, 252),       // HBlank
+            (0x20, 456),       // OAM scan of line 1, line 0 was already in mode 2
+            (0x10, 456 * 144), // VBlank
+        ];
+        for (select, first) in sources {
+            let mut gpu: GPU = gpu(Renderer::Scanline);
+            let mut interrupts: Interrupts = no_interrupts();
+            gpu.write_reg(0xFF41, select, &mut interrupts);
+            // Enabling the mode 2 source during mode 2 requests right away
+            assert_eq!(stat_requested(&interrupts), select == 0x20);
+            interrupts.write_if(0);
+            run(&mut gpu, &mut interrupts, first - 1);
+            assert!(!stat_requested(&interrupts), "STAT {:02X} too early", select);
+            run(&mut gpu, &mut interrupts, 1);
+            assert!(stat_requested(&interrupts), "STAT {:02X}", select);
+        }
+    }
+
+    #[test]
+    fn lyc_interrupt_and_coincidence_flag() {
+        let mut gpu: GPU = gpu(Renderer::Scanline);
+        let mut interrupts: Interrupts = no_interrupts();
+        gpu.write_reg(0xFF45, 5, &mut interrupts);
+        gpu.write_reg(0xFF41, 0x40, &mut interrupts);
+        run(&mut gpu, &mut interrupts, 456 * 5 - 1);
+        assert!(!stat_requested(&interrupts));
+        assert_eq!(gpu.read_reg(0xFF41) & 0x04, 0);
+        run(&mut gpu, &mut interrupts, 1);
+        assert!(stat_requested(&interrupts));
+        assert_eq!(gpu.read_reg(0xFF41) & 0x04, 0x04);
+        // Writing LYC to the current line raises the line right away
+        interrupts.write_if(0);
+        gpu.write_reg(0xFF45, 0, &mut interrupts);
+        gpu.write_reg(0xFF45, 5, &mut interrupts);
+        assert!(stat_requested(&interrupts));
+    }
+
+    #[test]
+    fn stat_blocking() {
+        let mut gpu: GPU = gpu(Renderer::Scanline);
+        let mut interrupts: Interrupts = no_interrupts();
+        gpu.write_reg(0xFF45, 1, &mut interrupts);
+        gpu.write_reg(0xFF41, 0x48, &mut interrupts);
+        run(&mut gpu, &mut interrupts, 252);
+        assert!(stat_requested(&interrupts));
+        // LY = LYC takes over from HBlank with no gap, so line 1 requests nothing
+        interrupts.write_if(0);
+        run(&mut gpu, &mut interrupts, 456);
+        assert!(!stat_requested(&interrupts));
+        // Both sources drop on line 2 and HBlank raises the line again
+        run(&mut gpu, &mut interrupts, 456);
+        assert!(stat_requested(&interrupts));
+    }
+
+    /// Line 0 drawn by the scanline renderer with `setup` applied first
+    fn scanline_line(setup: impl Fn(&mut GPU)) -> Vec<u8> {
+        let mut gpu: GPU = gpu(Renderer::Scanline);
+        gpu.lcdc = LCDC & !0x20;
+        setup(&mut gpu);
+        run(&mut gpu, &mut no_interrupts(), 456);
+        return line(&gpu, 0).to_vec();
+    }
+
+    #[test]
+    fn scanline_background_scroll_and_palette() {
+        let drawn: Vec<u8> = scanline_line(|gpu| {
+            // Row r of tile 0 has color r % 4 in its first four pixels and COLUMNS after
+            for row in 0..8 {
+                gpu.write(row * 2, if row & 1 != 0 {0xF5} else {0x05});
+                gpu.write(row * 2 + 1, if row & 2 != 0 {0xF3} else {0x03});
+            }
+            gpu.scx = 2;
+            gpu.scy = 3;
+            gpu.bgp = 0x1B;
+        });
+        let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| {
+            let column: usize = (x + 2) % 8;
+            let color: usize = if column < 4 {3} else {column % 4};
+            return 3 - color as u8;
+        }).collect();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn scanline_window_line_counter() {
+        let mut gpu: GPU = gpu(Renderer::Scanline);
+        let mut interrupts: Interrupts = no_interrupts();
+        gpu.lcdc = LCDC;
+        // Window tile 1 has color r % 4 on row r
+        for row in 0..8u16 {
+            gpu.write(16 + row * 2, if row & 1 != 0 {0xFF} else {0x00});
+            gpu.write(16 + row * 2 + 1, if row & 2 != 0 {0xFF} else {0x00});
+        }
+        for i in 0..0x400 {
+            gpu.write(0x1C00 + i, 1);
+        }
+        gpu.wx = 7 + 16;
+        gpu.wy = 0;
+        gpu.start_line();
+        // Drawn on lines 0 and 1, hidden on 2 and 3, back on line 4 with its third row
+        let enabled: [bool; 5] = [true, true, false, false, true];
+        for on in enabled {
+            gpu.lcdc = if on {LCDC} else {LCDC & !0x20};
+            run(&mut gpu, &mut interrupts, 456);
+        }
+        for (ly, window_row) in [(0, Some(0)), (1, Some(1)), (2, None), (4, Some(2))] {
+            let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| match window_row {
+                Some(row) if x >= 16 => row,
+                _ => 0,
+            }).collect();
+            assert_eq!(line(&gpu, ly), &expected[..], "line {}", ly);
+        }
+    }
+
+    #[test]
+    fn scanline_sprites() {
+        let drawn: Vec<u8> = scanline_line(|gpu| {
+            set_tile(gpu, 1, (0x80, 0x00));
+            set_tile(gpu, 2, solid(3));
+            set_tile(gpu, 3, solid(1));
+            set_tile(gpu, 4, solid(2));
+            gpu.obp1 = 0x1B;
+            // Background color 2 under x 80 - 87
+            gpu.write(0x1800 + 10, 4);
+            // Only the first pixel is opaque, flipped it's the last
+            set_sprite(gpu, 0, 16, 8 + 10, 1, 0x00);
+            set_sprite(gpu, 1, 16, 8 + 30, 1, 0x20);
+            set_sprite(gpu, 2, 16, 8 + 40, 1, 0x10);
+            // The earlier OAM entry loses to the smaller X
+            set_sprite(gpu, 3, 16, 8 + 52, 2, 0x00);
+            set_sprite(gpu, 4, 16, 8 + 50, 3, 0x00);
+            // Behind background colors 1 - 3
+            set_sprite(gpu, 5, 16, 8 + 84, 2, 0x80);
+        });
+        let mut expected: Vec<u8> = vec![0; SCREEN_WIDTH];
+        expected[10] = 1;
+        expected[37] = 1;
+        expected[40] = 2;
+        expected[50..58].fill(1);
+        expected[58..60].fill(3);
+        expected[80..88].fill(2);
+        expected[88..92].fill(3);
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn scanline_ten_sprites_per_line() {
+        let drawn: Vec<u8> = scanline_line(|gpu| {
+            set_tile(gpu, 1, solid(3));
+            for i in 0..11 {
+                set_sprite(gpu, i, 16, 8 + i as u8 * 8, 1, 0x00);
+            }
+        });
+        let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| if x < 80 {3} else {0}).collect();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn scanline_tall_sprites() {
+        let drawn: Vec<u8> = scanline_line(|gpu| {
+            gpu.lcdc |= 0x04;
+            set_tile(gpu, 6, solid(1));
+            set_tile(gpu, 7, solid(2));
+            // Line 0 is the 9th row, from the second tile, bit 0 of the tile number is ignored
+            set_sprite(gpu, 0, 16 - 8, 8, 7, 0x00);
+            set_sprite(gpu, 1, 16 - 8, 16, 6, 0x40);
+        });
+        let expected: Vec<u8> = (0..SCREEN_WIDTH).map(|x| match x {
+            0..=7 => 2,
+            8..=15 => 1,
+            _ => 0,
+        }).collect();
+        assert_eq!(drawn, expected);
+    }
+}
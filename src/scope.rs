@@ -0,0 +1,140 @@
+////////////////
+/// 
+/// scope.rs
+/// 
+/// Sources:
+/// https://docs.rs/minifb/0.23.0/minifb/ - window and keyboard input
+/// 
+/// Debug window with an oscilloscope for each APU channel, stacked from channel 1 at the
+/// top to channel 4 at the bottom. Each view shows the channel's DAC output from -1.0 at
+/// the bottom edge to 1.0 at the top, starting at a rising edge through the middle of the
+/// wave so periodic sounds hold still.
+/// 
+/// Keys 1 - 4 toggle muting a channel, Shift + 1 - 4 toggle soloing it. Channels that
+/// can't be heard are drawn dimmed.
+/// 
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+use crate::apu::{CHANNELS, SCOPE_LENGTH};
+
+/// Samples across each view
+const WIDTH: usize = 256;
+/// Pixels from top to bottom of each view
+const HEIGHT: usize = 64;
+const COLORS: [u32; CHANNELS] = [0xFF5050, 0x50FF50, 0x5090FF, 0xFFD040];
+const DIM_COLOR: u32 = 0x404040;
+const AXIS_COLOR: u32 = 0x202020;
+const MUTE_KEYS: [Key; CHANNELS] = [Key::Key1, Key::Key2, Key::Key3, Key::Key4];
+
+/*
+ * window: the minifb window
+ * buffer: pixels handed to minifb
+ * muted, soloed: mute and solo state set from the keyboard
+ * changed: mute or solo state changed since the last poll
+ */
+pub struct ScopeWindow {
+    window: Window,
+    buffer: Vec<u32>,
+    muted: [bool; CHANNELS],
+    soloed: [bool; CHANNELS],
+    changed: bool,
+}
+
+impl ScopeWindow {
+    /// Open the window, fails if it can't be opened
+    pub fn new(title: &str) -> Result<Self, minifb::Error> {
+        let options: WindowOptions = WindowOptions {
+            scale: Scale::X2,
+            ..WindowOptions::default()
+        };
+        let mut window: Window = Window::new(title, WIDTH, HEIGHT * CHANNELS, options)?;
+        // The main window already paces emulation
+        window.limit_update_rate(None);
+        Ok(Self {
+            window,
+            buffer: vec![0; WIDTH * HEIGHT * CHANNELS],
+            muted: [false; CHANNELS],
+            soloed: [false; CHANNELS],
+            changed: false,
+        })
+    }
+
+    pub fn is_open(&self) -> bool {
+        return self.window.is_open();
+    }
+
+    /// Draw each channel's recent output, `scopes` as from CPU::channel_scope
+    pub fn draw(&mut self, scopes: &[Vec<f32>; CHANNELS]) {
+        self.buffer.fill(0);
+        let solo: bool = self.soloed.contains(&true);
+        for (channel, samples) in scopes.iter().enumerate() {
+            let audible: bool = !self.muted[channel] && (!solo || self.soloed[channel]);
+            let color: u32 = if audible {COLORS[channel]} else {DIM_COLOR};
+            let top: usize = channel * HEIGHT;
+            for x in 0..WIDTH {
+                self.buffer[(top + HEIGHT / 2) * WIDTH + x] = AXIS_COLOR;
+            }
+            let start: usize = trigger(samples);
+            let mut last: usize = row(samples[start]);
+            for x in 0..WIDTH {
+                let y: usize = row(samples[start + x]);
+                // Fill between neighbouring samples so edges are drawn as lines
+                for line in y.min(last)..=y.max(last) {
+                    self.buffer[(top + line) * WIDTH + x] = color;
+                }
+                last = y;
+            }
+        }
+        self.poll_keys();
+        if let Err(err) = self.window.update_with_buffer(&self.buffer, WIDTH, HEIGHT * CHANNELS) {
+            eprintln!("Failed to update window: {}", err);
+        }
+    }
+
+    fn poll_keys(&mut self) {
+        let shift: bool = self.window.is_key_down(Key::LeftShift) || self.window.is_key_down(Key::RightShift);
+        for key in self.window.get_keys_pressed(KeyRepeat::No) {
+            if let Some(channel) = MUTE_KEYS.iter().position(|k| *k == key) {
+                if shift {
+                    self.soloed[channel] = !self.soloed[channel];
+                }
+                else {
+                    self.muted[channel] = !self.muted[channel];
+                }
+                self.changed = true;
+            }
+        }
+    }
+
+    /// Mute and solo state of each channel if it changed since the last call
+    pub fn poll_controls(&mut self) -> Option<([bool; CHANNELS], [bool; CHANNELS])> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        return Some((self.muted, self.soloed));
+    }
+}
+
+/// Start of the view: the latest rising edge through the middle of the wave that still
+/// leaves a full view after it, or just the latest samples if there is none
+fn trigger(samples: &[f32]) -> usize {
+    let latest: usize = SCOPE_LENGTH - WIDTH;
+    let min: f32 = samples.iter().cloned().fold(f32::MAX, f32::min);
+    let max: f32 = samples.iter().cloned().fold(f32::MIN, f32::max);
+    let middle: f32 = (min + max) / 2.0;
+    if max - min < 0.01 {
+        return latest;
+    }
+    for i in (1..=latest).rev() {
+        if samples[i - 1] < middle && samples[i] >= middle {
+            return i;
+        }
+    }
+    return latest;
+}
+
+/// Pixel row within a view for a sample, 1.0 at the top
+fn row(sample: f32) -> usize {
+    let y: f32 = (1.0 - sample.clamp(-1.0, 1.0)) / 2.0 * (HEIGHT - 1) as f32;
+    return y.round() as usize;
+}
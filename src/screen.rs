@@ -1,22 +1,151 @@
-use minifb::Window;
-use minifb::WindowOptions;
+////////////////
+/// 
+/// screen.rs
+/// 
+/// Sources:
+/// https://docs.rs/minifb/0.23.0/minifb/ - window and framebuffer
+/// 
+/// Shows the PPU framebuffer in a resizable window. The 160x144 image is scaled by the
+/// largest whole number that fits the window and centered, the rest is left black,
+/// so pixels stay square and evenly sized at any window size.
+/// 
+/// Keyboard state is read whenever the window is updated and turned into joypad
+/// button presses through the key map.
+/// 
+/// Hardware state with no picture of its own, the rumble motor and a CPU lockup, is shown
+/// in the title bar. It is only updated when a frame is presented, since rumble can be
+/// switched many times within one frame.
+/// 
+use minifb::{ScaleMode, Window, WindowOptions};
+use std::time::Duration;
+use crate::joypad::{Button, BUTTONS};
+use crate::keymap::KeyMap;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/// Window scale when it opens
+const DEFAULT_SCALE: usize = 4;
+/// One DMG frame, 70224 clocks at 4.194304 MHz
+const FRAME_TIME: Duration = Duration::from_micros(16742);
+/// Shades 0 - 3, white to black
+const PALETTE: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+/*
+ * window: the minifb window
+ * buffer: window sized pixels handed to minifb
+ * frame: last frame shown, kept to redraw on resize
+ * keymap: keyboard keys for each button
+ * buttons: button state last reported, in BUTTONS order
+ * title: window title without the status
+ * rumble: rumble motor running
+ * lockup: description of the lockup once the CPU has locked up
+ * shown_title: title currently on the window
+ */
 pub struct Screen{
-    window: Window
+    window: Window,
+    buffer: Vec<u32>,
+    frame: Vec<u8>,
+    keymap: KeyMap,
+    buttons: [bool; 8],
+    title: String,
+    rumble: bool,
+    lockup: Option<String>,
+    shown_title: String,
 }
 impl Screen{
-    pub fn new() -> Self{
-        Self { 
-            window: 
-                match Window::new("Test", 640, 400, WindowOptions::default()) 
-                    {
-                        Ok(win) => win,
-                        Err(err) => {
-                            panic!("Unable to create window {}", err);
-                        }
-                    } 
+    /// Open the window, fails where no window can be opened, like a machine without a display
+    pub fn new(title: &str, keymap: KeyMap) -> Result<Self, minifb::Error>{
+        let options: WindowOptions = WindowOptions {
+            resize: true,
+            scale_mode: ScaleMode::UpperLeft,
+            ..WindowOptions::default()
+        };
+        let mut window: Window =
+            Window::new(title, SCREEN_WIDTH * DEFAULT_SCALE, SCREEN_HEIGHT * DEFAULT_SCALE, options)?;
+        // Presenting a frame waits out the rest of the frame time, which paces emulation to 59.7 fps
+        window.limit_update_rate(Some(FRAME_TIME));
+        Ok(Self {
+            window,
+            buffer: Vec::new(),
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            keymap,
+            buttons: [false; 8],
+            title: title.to_string(),
+            rumble: false,
+            lockup: None,
+            shown_title: title.to_string(),
+        })
+    }
+
+    /// Buttons pressed or released since the last call, as of the last window update
+    pub fn poll_buttons(&mut self) -> Vec<(Button, bool)>{
+        let keys: Vec<minifb::Key> = self.window.get_keys();
+        let mut changes: Vec<(Button, bool)> = Vec::new();
+        for (i, button) in BUTTONS.iter().enumerate() {
+            let pressed: bool = self.keymap.pressed(*button, &keys);
+            if pressed != self.buttons[i] {
+                self.buttons[i] = pressed;
+                changes.push((*button, pressed));
+            }
+        }
+        return changes;
+    }
+
+    pub fn set_rumble(&mut self, on: bool){
+        self.rumble = on;
+    }
+
+    /// The CPU ran an illegal opcode and stopped for good
+    pub fn set_lockup(&mut self, opcode: u8, pc: u16){
+        self.lockup = Some(format!("locked up on opcode {:02X} at {:04X}", opcode, pc));
+    }
+
+    /// False once the window's close button was pressed
+    pub fn is_open(&self) -> bool{
+        return self.window.is_open();
+    }
+
+    /// Show a new 160x144 frame of shades
+    pub fn draw(&mut self, frame: &[u8]){
+        self.frame.copy_from_slice(frame);
+        self.present();
+    }
+
+    /// Redraw the last frame and handle window events, for when the PPU isn't producing frames
+    pub fn update(&mut self){
+        self.present();
+    }
+
+    fn present(&mut self){
+        let mut title: String = self.title.clone();
+        if let Some(lockup) = &self.lockup {
+            title = format!("{} [{}]", title, lockup);
+        }
+        if self.rumble {
+            title = format!("{} [rumble]", title);
+        }
+        if title != self.shown_title {
+            self.window.set_title(&title);
+            self.shown_title = title;
+        }
+
+        let (width, height): (usize, usize) = self.window.get_size();
+        let (width, height): (usize, usize) = (width.max(1), height.max(1));
+        let scale: usize = (width / SCREEN_WIDTH).min(height / SCREEN_HEIGHT).max(1);
+        // Centered, clipped if the window is smaller than the screen
+        let left: usize = width.saturating_sub(SCREEN_WIDTH * scale) / 2;
+        let top: usize = height.saturating_sub(SCREEN_HEIGHT * scale) / 2;
+
+        self.buffer.clear();
+        self.buffer.resize(width * height, 0);
+        for y in 0..height.min(SCREEN_HEIGHT * scale) {
+            let src: usize = (y / scale) * SCREEN_WIDTH;
+            let dst: usize = (top + y) * width + left;
+            for x in 0..(width - left).min(SCREEN_WIDTH * scale) {
+                self.buffer[dst + x] = PALETTE[self.frame[src + x / scale] as usize & 3];
             }
+        }
+        if let Err(err) = self.window.update_with_buffer(&self.buffer, width, height) {
+            eprintln!("Failed to update window: {}", err);
+        }
     }
-    // pub fn window_show(&mut self){
-    //     self.window.g
-    // }
 }
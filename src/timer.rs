@@ -1,66 +1,138 @@
+////////////////
+/// 
+/// timer.rs
+/// 
+/// Sources:
+/// https://gbdev.io/pandocs/Timer_and_Divider_Registers.html - registers
+/// https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html - falling edge and reload timing
+/// 
+/// 0xFF04 : DIV  - upper 8 bits of the internal 16 bit system counter
+/// 0xFF05 : TIMA - timer counter, requests the timer interrupt on overflow
+/// 0xFF06 : TMA  - loaded into TIMA after an overflow
+/// 0xFF07 : TAC  - bit 2 enable, bits 0-1 clock select
+/// 
+/// TIMA isn't clocked by its own divider. It increments whenever
+/// (system counter bit selected by TAC) AND (TAC enable) goes from 1 to 0,
+/// so writing DIV or TAC can increment it too.
+/// 
+/// TAC  Frequency  Counter bit
+///  00   4096 Hz    9
+///  01 262144 Hz    3
+///  10  65536 Hz    5
+///  11  16384 Hz    7
+/// 
+/// After an overflow TIMA reads 0x00 for one machine cycle, then TMA is loaded and
+/// the interrupt requested. Writing TIMA in that first cycle cancels the reload,
+/// during the reload cycle TIMA writes are ignored and TMA writes go through to TIMA.
+/// 
+use crate::interrupt::{Interrupt, Interrupts};
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Running,
+    /// TIMA overflowed and reads 0x00, TMA is loaded on the next cycle
+    Overflow,
+    /// TMA was loaded into TIMA this cycle
+    Reloading,
+}
+
 pub struct Timer{
     div: u16,
     tima: u8,
     tma: u8,
     tac: u8,
+    state: State,
 }
 impl Timer{
     pub fn new()->Self{
+        // State left behind by the DMG boot ROM
         Self{
-            div: 0xAC00,
-            tima: 0xFF05,
-            tma: 0xFF06,
-            tac: 0xFF07,
+            div: 0xABCC,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0xF8,
+            state: State::Running,
         }
     }
 
-    // returns whether or not we need to request interrupt
-    pub fn tick(&mut self) -> bool{
-        let prev_dv:: u16 = self.div;
-        self.div += 1;
-        let mut update: bool = false;
-        match self.tac & 0b11 {
-            0b00 => {
-                update = (prev_div & (1 << 9)) && (!(ctx.div & (1 << 9)));
-            },
-            0b01 => {
-                update = (prev_div & (1 << 3)) && (!(ctx.div & (1 << 3)));
-            },
-            0b10 => {
-                update = (prev_div & (1 << 5)) && (!(ctx.div & (1 << 5)));
+    /// Advance the system counter by one machine cycle (4 clocks)
+    pub fn tick(&mut self, interrupts: &mut Interrupts){
+        match self.state {
+            State::Overflow => {
+                self.tima = self.tma;
+                interrupts.request(Interrupt::Timer);
+                self.state = State::Reloading;
             },
-            0b11 => {
-                update = (prev_div & (1 << 7)) && (!(ctx.div & (1 << 7)));
-            }
+            State::Reloading => self.state = State::Running,
+            State::Running => {},
         }
+        let old: bool = self.signal();
+        self.div = self.div.wrapping_add(4);
+        self.detect_edge(old);
+    }
 
-        if (update && self.tac & (1 << 2)) {
-            self.tima == 1;
+    /// Clears the system counter, which can be a falling edge for TIMA
+    pub fn reset_div(&mut self){
+        let old: bool = self.signal();
+        self.div = 0;
+        self.detect_edge(old);
+    }
 
-            if (self.tima == 0xFF) {
-                self.tima = self.tma;
+    /// The whole 16 bit system counter, DIV is its upper byte
+    pub fn counter(&self) -> u16{
+        return self.div;
+    }
 
-                // cpu_request_interrupt(IT_TIMER);
-                //set interrupt flag
-                return true;
+    /// Input of the falling edge detector
+    fn signal(&self) -> bool{
+        let bit: u16 = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        return self.tac & 0b100 != 0 && self.div & (1 << bit) != 0;
+    }
+
+    fn detect_edge(&mut self, old: bool){
+        if old && !self.signal() {
+            let (tima, overflow): (u8, bool) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            if overflow {
+                self.state = State::Overflow;
             }
         }
-        return false;
     }
 
     pub fn write(&mut self, addr: u16, data: u8){
         match addr {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = data,
-            0xFF06 => self.tma = data,
-            0xFF07 => self.tac = data,
+            0xFF04 => self.reset_div(),
+            0xFF05 => match self.state {
+                State::Overflow => {
+                    self.tima = data;
+                    self.state = State::Running;
+                },
+                State::Reloading => {},
+                State::Running => self.tima = data,
+            },
+            0xFF06 => {
+                self.tma = data;
+                if self.state == State::Reloading {
+                    self.tima = data;
+                }
+            },
+            0xFF07 => {
+                let old: bool = self.signal();
+                self.tac = data | 0xF8;
+                self.detect_edge(old);
+            },
             _=> panic!("Invalid timer write")
         }
     }
 
-    pub fn read(&mut self, addr: u16){
+    pub fn read(&self, addr: u16) -> u8{
         match addr {
-            0xFF04 => self.div >> 8,
+            0xFF04 => (self.div >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
             0xFF07 => self.tac,
@@ -68,3 +140,118 @@ impl Timer{
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timer with the system counter cleared and TAC set, so no edge is pending
+    fn timer(tac: u8) -> Timer{
+        let mut timer: Timer = Timer::new();
+        timer.div = 0;
+        timer.write(0xFF07, tac);
+        return timer;
+    }
+
+    fn timer_requested(interrupts: &Interrupts) -> bool{
+        return interrupts.read_if() & Interrupt::Timer.bit() != 0;
+    }
+
+    #[test]
+    fn increments_on_falling_edge(){
+        let mut interrupts: Interrupts = Interrupts::new();
+        // Counter bit 3, TIMA steps every 16 clocks (4 machine cycles)
+        let mut timer: Timer = timer(0x05);
+        for _ in 0..3 {
+            timer.tick(&mut interrupts);
+        }
+        assert_eq!(timer.read(0xFF05), 0);
+        timer.tick(&mut interrupts);
+        assert_eq!(timer.read(0xFF05), 1);
+        for _ in 0..4 {
+            timer.tick(&mut interrupts);
+        }
+        assert_eq!(timer.read(0xFF05), 2);
+    }
+
+    #[test]
+    fn disabled_timer_doesnt_count(){
+        let mut interrupts: Interrupts = Interrupts::new();
+        let mut timer: Timer = timer(0x01);
+        for _ in 0..64 {
+            timer.tick(&mut interrupts);
+        }
+        assert_eq!(timer.read(0xFF05), 0);
+    }
+
+    #[test]
+    fn tac_write_can_cause_an_edge(){
+        let mut timer: Timer = timer(0x05);
+        timer.div = 0x0008;
+        // Disabling the timer while the selected bit is set drops the signal
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 1);
+    }
+
+    #[test]
+    fn div_reset_can_cause_an_edge(){
+        let mut timer: Timer = timer(0x04);
+        timer.div = 0x0200;
+        timer.reset_div();
+        assert_eq!(timer.read(0xFF04), 0);
+        assert_eq!(timer.read(0xFF05), 1);
+        // With the selected bit clear a reset does nothing
+        timer.reset_div();
+        assert_eq!(timer.read(0xFF05), 1);
+    }
+
+    /// Timer one tick away from overflowing TIMA, with TMA 0x42
+    fn about_to_overflow() -> Timer{
+        let mut timer: Timer = timer(0x05);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF06, 0x42);
+        timer.div = 0x000C;
+        return timer;
+    }
+
+    #[test]
+    fn overflow_reloads_a_cycle_later(){
+        let mut interrupts: Interrupts = Interrupts::new();
+        let mut timer: Timer = about_to_overflow();
+        timer.tick(&mut interrupts);
+        // TIMA reads 0 for a cycle before TMA is loaded and the interrupt requested
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(!timer_requested(&interrupts));
+        timer.tick(&mut interrupts);
+        assert_eq!(timer.read(0xFF05), 0x42);
+        assert!(timer_requested(&interrupts));
+    }
+
+    #[test]
+    fn tima_write_during_overflow_cancels_reload(){
+        let mut interrupts: Interrupts = Interrupts::new();
+        let mut timer: Timer = about_to_overflow();
+        timer.tick(&mut interrupts);
+        timer.write(0xFF05, 0x10);
+        timer.tick(&mut interrupts);
+        assert_eq!(timer.read(0xFF05), 0x10);
+        assert!(!timer_requested(&interrupts));
+    }
+
+    #[test]
+    fn writes_during_reload(){
+        let mut interrupts: Interrupts = Interrupts::new();
+        let mut timer: Timer = about_to_overflow();
+        timer.tick(&mut interrupts);
+        timer.tick(&mut interrupts);
+        // TIMA writes are ignored in the reload cycle, TMA writes go through to TIMA
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x42);
+        timer.write(0xFF06, 0x33);
+        assert_eq!(timer.read(0xFF05), 0x33);
+        // Back to normal a cycle later
+        timer.tick(&mut interrupts);
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x10);
+    }
+}
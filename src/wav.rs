@@ -0,0 +1,154 @@
+////////////////
+/// 
+/// wav.rs
+/// 
+/// Sources:
+/// http://soundfile.sapp.org/doc/WaveFormat/ - canonical WAVE file layout
+/// 
+/// Streams APU output to 16 bit PCM WAV files, so sound can be captured on machines
+/// without an audio device and compared between runs. The header is written up front
+/// with empty sizes and patched once recording finishes, or when the writer is dropped.
+/// 
+/// A Recorder writes the mixed stereo output to the given path and, when asked for,
+/// each channel's DAC output as mono files next to it: out.wav, out.ch1.wav ... out.ch4.wav.
+/// 
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+/// Most sample data a WAV file can hold, the RIFF chunk size is 32 bits
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+/*
+ * file: the open .wav file, None once finished
+ * data_size: bytes of sample data written so far
+ */
+pub struct WavWriter {
+    file: Option<BufWriter<File>>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        let mut file: BufWriter<File> = BufWriter::new(File::create(path)?);
+        let block_align: u16 = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { file: Some(file), data_size: 0 })
+    }
+
+    /// Append samples from -1.0 to 1.0, interleaved when there is more than one channel.
+    /// Fails without writing anything once the file would go past the 4 GiB WAV limit
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let file: &mut BufWriter<File> = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let size: u64 = self.data_size as u64 + samples.len() as u64 * 2;
+        if size > MAX_DATA_SIZE as u64 {
+            return Err(io::Error::new(io::ErrorKind::Other, "WAV file is full, the format is limited to 4 GiB"));
+        }
+        for sample in samples {
+            let value: i16 = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size = size as u32;
+        return Ok(());
+    }
+
+    /// Fill in the chunk sizes and close the file, later writes are ignored
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut file: BufWriter<File> = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        file.write_all(&self.data_size.to_le_bytes())?;
+        file.flush()?;
+        return Ok(());
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            eprintln!("Failed to finish WAV file: {}", err);
+        }
+    }
+}
+
+/*
+ * mix: stereo output as heard
+ * channels: DAC output of channels 1 - 4, only when recording them separately
+ */
+pub struct Recorder {
+    mix: WavWriter,
+    channels: Option<[WavWriter; 4]>,
+}
+
+impl Recorder {
+    /// Start recording to `path`, plus one file per channel when `separate` is set
+    pub fn create(path: &Path, sample_rate: u32, separate: bool) -> io::Result<Self> {
+        let mix: WavWriter = WavWriter::create(path, sample_rate, 2)?;
+        let channels: Option<[WavWriter; 4]> = if separate {
+            let channel = |n: usize| WavWriter::create(&path.with_extension(format!("ch{}.wav", n)), sample_rate, 1);
+            Some([channel(1)?, channel(2)?, channel(3)?, channel(4)?])
+        } else {
+            None
+        };
+        Ok(Self { mix, channels })
+    }
+
+    /// Add one host sample: the stereo mix and each channel's output
+    pub fn write(&mut self, mix: [f32; 2], channels: [f32; 4]) -> io::Result<()> {
+        self.mix.write(&mix)?;
+        if let Some(writers) = &mut self.channels {
+            for (writer, sample) in writers.iter_mut().zip(channels.iter()) {
+                writer.write(&[*sample])?;
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.mix.finish()?;
+        if let Some(writers) = &mut self.channels {
+            for writer in writers.iter_mut() {
+                writer.finish()?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_the_size_limit() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("gb_at2_limit_{}.wav", std::process::id()));
+        let mut writer: WavWriter = WavWriter::create(&path, 48000, 1).unwrap();
+        writer.data_size = MAX_DATA_SIZE - 2;
+        assert!(writer.write(&[0.0]).is_ok());
+        assert!(writer.write(&[0.0]).is_err());
+        assert_eq!(writer.data_size, MAX_DATA_SIZE);
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const SAMPLE_RATE: u32 = 48000;
+const FRAMES: u32 = 10;
+/// Host samples in one frame, 70224 clocks at 4.194304 MHz
+const SAMPLES_PER_FRAME: f64 = SAMPLE_RATE as f64 * 70224.0 / 4194304.0;
+
+/// 32 KiB ROM that turns the APU on, starts a 512 Hz square wave on channel 1 and spins
+fn tone_rom() -> Vec<u8> {
+    let mut rom: Vec<u8> = vec![0; 0x8000];
+    // Entry point: NOP, JP 0x0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x134..0x138].copy_from_slice(b"TONE");
+    let program: [u8; 29] = [
+        0x3E, 0x80, 0xE0, 0x26, // NR52: APU on
+        0x3E, 0x77, 0xE0, 0x24, // NR50: full volume both sides
+        0x3E, 0x11, 0xE0, 0x25, // NR51: channel 1 to both sides
+        0x3E, 0x80, 0xE0, 0x11, // NR11: 50% duty
+        0x3E, 0xF0, 0xE0, 0x12, // NR12: volume 15, no envelope
+        0xAF, 0xE0, 0x13,       // NR13: period low 0x00
+        0x3E, 0x87, 0xE0, 0x14, // NR14: trigger, period high 7
+        0x18, 0xFE,             // JR -2
+    ];
+    rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+    return rom;
+}
+
+/// Fresh directory for one test's files
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir: PathBuf = std::env::temp_dir().join(format!("gb_at2_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+/// Run the emulator without a window in `dir`, where it also writes its log
+fn run_headless(dir: &Path, args: &[&str]) -> Output {
+    return Command::new(env!("CARGO_BIN_EXE_gb_at2"))
+        .current_dir(dir)
+        .arg("--headless")
+        .args(args)
+        .output()
+        .unwrap();
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes([data[offset], data[offset + 1]]);
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+/// Check the header of a 16 bit PCM WAV file and return its samples
+fn read_wav(path: &Path, channels: u16) -> Vec<i16> {
+    let data: Vec<u8> = fs::read(path).unwrap();
+    assert!(data.len() >= 44, "{} is too short", path.display());
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+    assert_eq!(&data[8..12], b"WAVE");
+    assert_eq!(&data[12..16], b"fmt ");
+    assert_eq!(u32_at(&data, 16), 16);
+    assert_eq!(u16_at(&data, 20), 1);
+    assert_eq!(u16_at(&data, 22), channels);
+    assert_eq!(u32_at(&data, 24), SAMPLE_RATE);
+    assert_eq!(u32_at(&data, 28), SAMPLE_RATE * channels as u32 * 2);
+    assert_eq!(u16_at(&data, 32), channels * 2);
+    assert_eq!(u16_at(&data, 34), 16);
+    assert_eq!(&data[36..40], b"data");
+    assert_eq!(u32_at(&data, 40) as usize, data.len() - 44);
+    return data[44..].chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+}
+
+#[test]
+fn records_a_headless_run() {
+    let dir: PathBuf = scratch_dir("record");
+    fs::write(dir.join("tone.gb"), tone_rom()).unwrap();
+    let output: Output = run_headless(&dir, &["--frames", &FRAMES.to_string(), "--record-audio", "out.wav", "--record-channels", "tone.gb"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let mix: Vec<i16> = read_wav(&dir.join("out.wav"), 2);
+    assert_eq!(mix.len() % 2, 0);
+    // The first frame ends partway through, the screen starts at the top of the picture
+    let frames: f64 = (mix.len() / 2) as f64 / SAMPLES_PER_FRAME;
+    assert!(frames > (FRAMES - 1) as f64 && frames <= FRAMES as f64 + 0.01, "{} frames recorded", frames);
+    assert!(mix.iter().any(|sample| *sample != mix[0]), "the tone is missing from the mix");
+
+    for channel in 1..=4 {
+        let samples: Vec<i16> = read_wav(&dir.join(format!("out.ch{}.wav", channel)), 1);
+        assert_eq!(samples.len(), mix.len() / 2);
+        let silent: bool = samples.iter().all(|sample| *sample == samples[0]);
+        assert_eq!(silent, channel != 1, "channel {}", channel);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn headless_needs_a_frame_count() {
+    let dir: PathBuf = scratch_dir("no_frames");
+    fs::write(dir.join("tone.gb"), tone_rom()).unwrap();
+    let output: Output = run_headless(&dir, &["tone.gb"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage:"));
+    fs::remove_dir_all(&dir).unwrap();
+}